@@ -0,0 +1,7 @@
+fn main() {
+    // Compiled grammars are cached per target, see `loader::build_from_source`
+    println!(
+        "cargo:rustc-env=GRAFT_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
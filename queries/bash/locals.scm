@@ -0,0 +1,15 @@
+; Variables are dynamically scoped, so every definition is visible in the whole file.
+
+; Definitions
+;------------
+
+(variable_assignment
+  name: (variable_name) @local.definition) @local.declaration
+
+(for_statement
+  variable: (variable_name) @local.definition)
+
+; References
+;-----------
+
+(variable_name) @local.reference
@@ -0,0 +1,6 @@
+; Shell form of RUN instructions
+
+((run_instruction
+  (shell_command) @injection.content)
+ (#set! injection.language "bash")
+ (#set! injection.include-children))
@@ -0,0 +1,41 @@
+; Scopes
+;-------
+
+[
+  (function_declaration)
+  (method_declaration)
+  (func_literal)
+  (block)
+  (if_statement)
+  (for_statement)
+  (expression_switch_statement)
+  (type_switch_statement)
+] @local.scope
+
+; Definitions
+;------------
+
+(parameter_declaration
+  name: (identifier) @local.definition)
+
+(variadic_parameter_declaration
+  name: (identifier) @local.definition)
+
+(short_var_declaration
+  left: (expression_list
+    (identifier) @local.definition)) @local.declaration
+
+(var_spec
+  name: (identifier) @local.definition) @local.declaration
+
+(const_spec
+  name: (identifier) @local.definition) @local.declaration
+
+(range_clause
+  left: (expression_list
+    (identifier) @local.definition))
+
+; References
+;-----------
+
+(identifier) @local.reference
@@ -0,0 +1,47 @@
+; Scopes
+;-------
+
+[
+  (method_declaration)
+  (constructor_declaration)
+  (lambda_expression)
+  (block)
+  (for_statement)
+  (enhanced_for_statement)
+  (catch_clause)
+  (try_with_resources_statement)
+] @local.scope
+
+; Definitions
+;------------
+
+(formal_parameter
+  name: (identifier) @local.definition)
+
+(spread_parameter
+  (variable_declarator
+    name: (identifier) @local.definition))
+
+(catch_formal_parameter
+  name: (identifier) @local.definition)
+
+(lambda_expression
+  parameters: (identifier) @local.definition)
+
+(inferred_parameters
+  (identifier) @local.definition)
+
+(local_variable_declaration
+  declarator: (variable_declarator
+    name: (identifier) @local.definition))
+
+(enhanced_for_statement
+  name: (identifier) @local.definition)
+
+(resource
+  name: (identifier) @local.definition)
+
+; References
+;-----------
+
+(identifier) @local.reference
@@ -0,0 +1,6 @@
+; Fenced code blocks, in the language named by their info string
+
+(fenced_code_block
+  (info_string) @injection.language
+  (code_fence_content) @injection.content
+  (#set! injection.include-children))
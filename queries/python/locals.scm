@@ -0,0 +1,52 @@
+; Scopes
+;-------
+
+[
+  (function_definition)
+  (lambda)
+  (list_comprehension)
+  (dictionary_comprehension)
+  (set_comprehension)
+  (generator_expression)
+] @local.scope
+
+; Definitions
+;------------
+
+(parameters
+  (identifier) @local.definition)
+
+(default_parameter
+  name: (identifier) @local.definition)
+
+(typed_parameter
+  (identifier) @local.definition)
+
+(typed_default_parameter
+  name: (identifier) @local.definition)
+
+(lambda_parameters
+  (identifier) @local.definition)
+
+(assignment
+  left: (identifier) @local.definition) @local.declaration
+
+(assignment
+  left: (pattern_list
+    (identifier) @local.definition)) @local.declaration
+
+(for_statement
+  left: (identifier) @local.definition)
+
+(for_in_clause
+  left: (identifier) @local.definition)
+
+(with_item
+  value: (as_pattern
+    alias: (as_pattern_target
+      (identifier) @local.definition)))
+
+; References
+;-----------
+
+(identifier) @local.reference
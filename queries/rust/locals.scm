@@ -0,0 +1,42 @@
+; Scopes
+;-------
+
+[
+  (block)
+  (function_item)
+  (closure_expression)
+  (for_expression)
+  (if_expression)
+  (while_expression)
+  (match_arm)
+] @local.scope
+
+; Definitions
+;------------
+
+(parameter
+  pattern: (identifier) @local.definition)
+
+(closure_parameters
+  (identifier) @local.definition)
+
+(let_declaration
+  pattern: (identifier) @local.definition) @local.declaration
+
+(let_condition
+  pattern: (identifier) @local.definition) @local.declaration
+
+(tuple_pattern
+  (identifier) @local.definition)
+
+(tuple_struct_pattern
+  "("
+  (identifier) @local.definition)
+
+(for_expression
+  pattern: (identifier) @local.definition)
+
+; References
+;-----------
+
+(identifier) @local.reference
@@ -0,0 +1,36 @@
+; Shell scripts in CI configuration: GitHub Actions `run`, GitLab CI `script` and friends.
+; `injection.skip-first-line` leaves out the `|` or `>` header of block scalars.
+
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (block_node (block_scalar) @injection.content)
+  (#any-of? @_key "run" "script" "before_script" "after_script")
+  (#set! injection.language "bash")
+  (#set! injection.skip-first-line))
+
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (flow_node (plain_scalar) @injection.content)
+  (#any-of? @_key "run" "script" "before_script" "after_script")
+  (#set! injection.language "bash")
+  (#set! injection.include-children))
+
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (block_node
+    (block_sequence
+      (block_sequence_item
+        (flow_node (plain_scalar) @injection.content))))
+  (#any-of? @_key "script" "before_script" "after_script")
+  (#set! injection.language "bash")
+  (#set! injection.include-children))
+
+(block_mapping_pair
+  key: (flow_node) @_key
+  value: (block_node
+    (block_sequence
+      (block_sequence_item
+        (block_node (block_scalar) @injection.content))))
+  (#any-of? @_key "script" "before_script" "after_script")
+  (#set! injection.language "bash")
+  (#set! injection.skip-first-line))
@@ -0,0 +1,68 @@
+use similar::TextDiff;
+use std::env;
+use std::path::Path;
+
+/// Renders a unified diff between `original` and `modified` with `a/` and `b/` headers,
+/// suitable for a pager or `git apply`. Returns an empty string when nothing changed.
+///
+/// Absolute paths are shown relative to the working directory when they are inside it, and
+/// without their leading `/` otherwise, so the headers always have one component to strip.
+pub fn unified_diff(path: &str, original: &str, modified: &str, context: usize) -> String {
+    if original == modified {
+        return String::new();
+    }
+
+    let path = relative_path(path);
+    TextDiff::from_lines(original, modified)
+        .unified_diff()
+        .context_radius(context)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+fn relative_path(path: &str) -> String {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    if !Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    env::current_dir()
+        .ok()
+        .and_then(|cwd| {
+            Path::new(path)
+                .strip_prefix(cwd)
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.trim_start_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff_headers() {
+        let diff = unified_diff("./src/main.rs", "a\nb\nc\n", "a\nx\nc\n", 1);
+        assert_eq!(
+            diff,
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_absolute_path() {
+        let cwd = env::current_dir().unwrap();
+        let inside = cwd.join("src/main.rs");
+        let diff = unified_diff(&inside.to_string_lossy(), "a\n", "b\n", 0);
+        assert!(diff.starts_with("--- a/src/main.rs\n+++ b/src/main.rs\n"));
+
+        let diff = unified_diff("/nonexistent/d.rs", "a\n", "b\n", 0);
+        assert!(diff.starts_with("--- a/nonexistent/d.rs\n+++ b/nonexistent/d.rs\n"));
+    }
+
+    #[test]
+    fn test_unified_diff_unchanged() {
+        assert!(unified_diff("main.rs", "a\n", "a\n", 3).is_empty());
+    }
+}
@@ -1,25 +1,37 @@
-use crate::graft::Transformer;
-use crate::graft::languages::LANGUAGES;
-use crate::graft::rules::RuleFile;
+use crate::graft::config::{self, ProjectConfig};
+use crate::graft::format::{self, FormatScope, Formatter};
+use crate::graft::injections;
+use crate::graft::languages;
+use crate::graft::rules::{Repeat, RuleFile};
+use crate::graft::{
+    Action, ApplyOptions, Diagnostic, Modification, OnError, Overlap, SerializablePoint,
+    Transformer,
+};
 use anyhow::{Context, Result, anyhow};
-use clap::Parser;
-use glob::glob;
+use clap::{Parser, Subcommand};
 use rayon::prelude::*;
 use serde::Serialize;
 use std::fs;
-use std::io::{self, Read};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+
+mod diff;
+mod rename;
+mod review;
+mod transaction;
+mod walk;
 
 #[derive(Parser, Debug)]
 #[command(
     author,
     version,
     about,
-    long_about = "Graft is a safe, structural code transformation tool. It uses Tree-sitter to parse source code into an AST, allowing you to rewrite code based on its structure rather than fragile regex patterns.\n\nExamples:\n  # Rewrite binary expressions (a + b -> add(a, b))\n  graft src/main.rs -q '(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target' -t 'add(${l}, ${r})'\n\n  # Rename function calls across multiple files\n  graft \"src/**/*.rs\" -q '(call_expression function: (identifier) @n (#eq? @n \"old\")) @target' -t 'new' -i\n\n  # Use a rule file for complex transformations\n  graft src/ -f rules.toml -i"
+    args_conflicts_with_subcommands = true,
+    long_about = "Graft is a safe, structural code transformation tool. It uses Tree-sitter to parse source code into an AST, allowing you to rewrite code based on its structure rather than fragile regex patterns.\n\nExamples:\n  # Rewrite binary expressions (a + b -> add(a, b))\n  graft src/main.rs -q '(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target' -t 'add(${l}, ${r})'\n\n  # Rename function calls across multiple files\n  graft \"src/**/*.rs\" -q '(call_expression function: (identifier) @n (#eq? @n \"old\")) @target' -t 'new' -i\n\n  # Use a rule file for complex transformations\n  graft src/ -f rules.toml -i\n\n  # Rename a function and all its calls\n  graft rename old_name new_name src/ --kind function -l rust -i"
 )]
 pub struct Cli {
-    /// Path to the source file(s) or glob pattern(s). Optional if reading from stdin.
+    /// Path to the source file(s), director(ies) or glob pattern(s). Optional if reading from stdin.
+    /// Directories are walked recursively, honoring `.gitignore`, `.ignore` and hidden files.
     pub files: Vec<String>,
 
     /// Tree-sitter query (S-expression). Capture the node to replace with `@target`.
@@ -32,7 +44,8 @@ pub struct Cli {
     #[arg(short, long, value_name = "TEMPLATE")]
     pub template: Vec<String>,
 
-    /// Path to a TOML rule file containing multiple queries and templates.
+    /// Path to a TOML rule file containing multiple queries and templates. Rules can constrain
+    /// their captures in a `[rules.where]` table, e.g. `args.count = ">= 2"`.
     #[arg(short = 'f', long, value_name = "FILE")]
     pub rule_file: Option<PathBuf>,
 
@@ -40,55 +53,200 @@ pub struct Cli {
     #[arg(short, long)]
     pub in_place: bool,
 
-    /// Language of the source code. Required if reading from stdin or if extension detection fails.
+    /// Language of the source code. Required if reading from stdin or if language detection fails.
     #[arg(short, long, value_name = "LANG")]
     pub language: Option<String>,
 
-    /// List all supported languages and their file extensions.
+    /// List all supported languages, their file extensions, and whether their grammars passed
+    /// validation.
     #[arg(long)]
     pub list_languages: bool,
 
     /// Output detailed modification metadata in JSON format.
     #[arg(long)]
     pub json: bool,
+
+    /// Print a unified diff of the changes instead of the transformed source.
+    #[arg(long, conflicts_with_all = ["in_place", "json"])]
+    pub diff: bool,
+
+    /// Number of context lines around each change in `--diff` output.
+    #[arg(short = 'U', long, value_name = "N", default_value_t = 3)]
+    pub context: usize,
+
+    /// Run every rule without writing anything and exit with status 1 if any file would change.
+    /// Prints which files and rules would produce modifications.
+    #[arg(long, conflicts_with_all = ["in_place", "diff"])]
+    pub check: bool,
+
+    /// Only transform files matching this glob when walking directories.
+    /// Defaults to files with a supported language extension. Can be specified multiple times.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files matching this glob. Can be specified multiple times.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Review every match before applying it and write the accepted changes in place.
+    #[arg(long, conflicts_with_all = ["json", "diff", "check"])]
+    pub interactive: bool,
+
+    /// Transform every file in memory first and write nothing unless all of them succeed.
+    /// Files are replaced atomically and their previous contents are journaled for `--undo`.
+    #[arg(long, conflicts_with_all = ["json", "diff", "check"])]
+    pub atomic: bool,
+
+    /// What to do when an edit produces a syntax error: `abort` the file, `skip` the edit, or
+    /// `keep` it. Applies to `--query` rules and to rule-file rules without `on_error`.
+    #[arg(long, value_name = "POLICY", default_value = "abort")]
+    pub on_error: OnError,
+
+    /// How to resolve nested matches: apply the `outermost` one, apply the `innermost` ones first
+    /// and then re-query the enclosing ones, or fail with an `error`. Skipped matches are reported.
+    /// Applies to `--query` rules and to rule-file rules without `overlap`.
+    #[arg(long, value_name = "POLICY", default_value = "outermost")]
+    pub overlap: Overlap,
+
+    /// How to edit each match's target: `replace` it, `insert_before` or `insert_after` it,
+    /// `delete` it, or `wrap` it in a template that contains the target capture.
+    /// Applies to `--query` rules and to rule-file rules without `action`.
+    #[arg(long, value_name = "ACTION", default_value = "replace")]
+    pub action: Action,
+
+    /// Re-apply rules until nothing matches anymore, up to MAX iterations (default: 100).
+    /// Applies to `--query` rules and to rule-file rules without `repeat`.
+    #[arg(
+        long,
+        value_name = "MAX",
+        num_args = 0..=1,
+        default_missing_value = "100",
+        value_parser = parse_repeat
+    )]
+    pub repeat: Option<usize>,
+
+    /// Rewrite files that already contain parse errors. Only errors newly introduced near an
+    /// edit are treated as failures.
+    #[arg(long)]
+    pub allow_existing_errors: bool,
+
+    /// Format rewritten files: `builtin` normalizes whitespace between tokens, `auto` runs the
+    /// usual formatter for the language (rustfmt, gofmt, black, prettier), and anything else is
+    /// a command that reads the source on stdin and writes it to stdout (`{file}` is replaced
+    /// with the file's path). Unchanged files are never formatted.
+    #[arg(long, value_name = "FORMATTER")]
+    pub format: Option<Formatter>,
+
+    /// Which part of a rewritten file the formatter may change: only the `changed` lines, or
+    /// the whole `file`.
+    #[arg(
+        long,
+        value_name = "SCOPE",
+        default_value = "changed",
+        requires = "format"
+    )]
+    pub format_scope: FormatScope,
+
+    /// Ignore the `graft.toml` project config, which is otherwise looked up in the working
+    /// directory and its ancestors, up to the root of the repository.
+    #[arg(long)]
+    pub no_config: bool,
+
+    /// Compile and load the `[grammars]` of the `graft.toml` project config. They run native
+    /// code, so only pass this for a config you trust.
+    #[arg(long, conflicts_with = "no_config")]
+    pub load_grammars: bool,
+
+    /// Restore the files written by the last `--atomic` run.
+    #[arg(long, exclusive = true)]
+    pub undo: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+fn parse_repeat(s: &str) -> std::result::Result<usize, String> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err(format!(
+            "Invalid repeat '{}'. Expected a number of iterations of at least 1",
+            s
+        )),
+        Ok(n) => Ok(n),
+    }
+}
+
+/// The options of a bare `graft` invocation, for building a [`Cli`] in code with struct update
+/// syntax.
+impl Default for Cli {
+    fn default() -> Self {
+        Self::parse_from(["graft"])
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Rename a function, type or field across files, updating its definition and all references.
+    /// Aborts without changing anything if the new name collides with an existing symbol or would
+    /// be shadowed by a local variable.
+    Rename(rename::RenameArgs),
 }
 
 #[derive(Serialize)]
 struct JsonOutput {
     status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    modifications: Option<Vec<crate::graft::Modification>>,
+    modifications: Option<Vec<Modification>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<Diagnostic>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    formatter_errors: Vec<FormatterError>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
 }
 
+/// A file whose rewrite succeeded but could not be formatted; it is kept unformatted.
+#[derive(Serialize)]
+struct FormatterError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    message: String,
+}
+
+struct FileOutcome {
+    original: String,
+    transformed: String,
+    modifications: Vec<Modification>,
+    /// Names of the rules that produced at least one modification, in application order.
+    changed_rules: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    /// Why `--format` failed, if it did.
+    formatter_error: Option<String>,
+}
+
+/// Decides whether a proposed edit is applied, given the rule name and the current source.
+type ReviewFn<'a> = dyn FnMut(&str, &str, &Modification) -> bool + 'a;
+
 struct ResolvedRule {
+    name: String,
     query: String,
     template: String,
     priority: i32,
+    options: ApplyOptions,
 }
 
+/// Whether a rule written for `rule_lang` applies to `target_lang`. Either can be a language
+/// name or any of its extensions, so `rust` matches `rs`.
 fn language_matches(rule_lang: &str, target_lang: &str) -> bool {
     if rule_lang == target_lang {
         return true;
     }
-    // Handle common aliases
-    let aliases = [
-        ("rust", "rs"),
-        ("javascript", "js"),
-        ("typescript", "ts"),
-        ("python", "py"),
-        ("markdown", "md"),
-        ("makefile", "make"),
-        ("makefile", "mk"),
-        ("dockerfile", "docker"),
-    ];
-    for (l1, l2) in aliases {
-        if (rule_lang == l1 && target_lang == l2) || (rule_lang == l2 && target_lang == l1) {
-            return true;
-        }
+    match (
+        languages::find_spec(rule_lang),
+        languages::find_spec(target_lang),
+    ) {
+        (Some(a), Some(b)) => a.name == b.name,
+        _ => false,
     }
-    false
 }
 
 pub fn run() -> Result<()> {
@@ -97,25 +255,58 @@ pub fn run() -> Result<()> {
 }
 
 pub fn run_with_args(cli: Cli) -> Result<()> {
+    // Undo needs no config, so a broken one cannot keep it from restoring files
+    if cli.undo {
+        let restored = transaction::undo(Path::new(transaction::JOURNAL_PATH))?;
+        eprintln!("Restored {} file(s)", restored);
+        return Ok(());
+    }
+
+    // Loaded before anything else that needs languages, since it can register grammars
+    let config = load_config(cli.no_config, cli.load_grammars)?;
+    if let Some(Command::Rename(args)) = &cli.command {
+        return rename::run(args, config.as_ref());
+    }
+
     if cli.list_languages {
-        println!("| Language | Extensions |");
-        println!("|---|---|");
-        for lang in LANGUAGES {
+        println!("| Language | Extensions | ABI | Status |");
+        println!("|---|---|---|---|");
+        for lang in languages::all() {
             let exts_str = lang
                 .extensions
                 .iter()
                 .map(|s| format!("`{}`", s))
                 .collect::<Vec<_>>()
                 .join(", ");
-            println!("| {} | {} |", lang.name, exts_str);
+            let status = match lang.health() {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("disabled: {}", e),
+            };
+            let abi = tree_sitter::Language::from(lang.grammar).abi_version();
+            println!("| {} | {} | {} | {} |", lang.name, exts_str, abi, status);
         }
         return Ok(());
     }
 
-    let rule_file = if let Some(ref path) = cli.rule_file {
-        Some(RuleFile::load(path)?)
-    } else {
-        None
+    if cli.atomic && !(cli.in_place || cli.interactive) {
+        return Err(anyhow!("--atomic requires --in-place or --interactive"));
+    }
+    if cli.atomic {
+        // Even a run that ends up writing nothing replaces the previous run's journal
+        transaction::clear(Path::new(transaction::JOURNAL_PATH))?;
+    }
+
+    let rule_file = match (&cli.rule_file, &config) {
+        (Some(path), _) => Some(RuleFile::load(path)?),
+        // The project's rule files are the default when no rules are given
+        (None, Some(config)) if cli.query.is_empty() && !config.rule_files.is_empty() => {
+            let mut rules = Vec::new();
+            for path in &config.rule_files {
+                rules.extend(RuleFile::load(path)?.rules);
+            }
+            Some(RuleFile { rules })
+        }
+        _ => None,
     };
 
     // If no rule file and no CLI query, error out (unless listing languages)
@@ -133,28 +324,29 @@ pub fn run_with_args(cli: Cli) -> Result<()> {
         ));
     }
 
-    // Collect all files from arguments (expanding globs)
-    let mut file_paths = Vec::new();
-    for pattern in &cli.files {
-        let entries =
-            glob(pattern).with_context(|| format!("Failed to read glob pattern: {}", pattern))?;
-        for entry in entries {
-            match entry {
-                Ok(path) => file_paths.push(path),
-                Err(e) => eprintln!("Warning: failed to read glob entry: {}", e),
-            }
-        }
+    // Collect all files from arguments (expanding globs and walking directories)
+    let file_paths = walk::collect_files(&cli.files, &cli.include, &cli.exclude, config.as_ref())?;
+
+    // A directory or glob that matches nothing must not fall back to stdin
+    if file_paths.is_empty() && !cli.files.is_empty() {
+        return Err(anyhow!("No files matched {}", cli.files.join(", ")));
     }
 
     // If no files provided, read from stdin
-    if file_paths.is_empty() {
+    if cli.files.is_empty() {
         if cli.in_place {
             return Err(anyhow!(
                 "--in-place is only supported when files are provided"
             ));
         }
+        if cli.interactive {
+            return Err(anyhow!(
+                "--interactive is only supported when files are provided"
+            ));
+        }
         let lang_name = cli
             .language
+            .clone()
             .ok_or_else(|| anyhow!("--language is required when reading from stdin"))?;
 
         let mut source = String::new();
@@ -162,149 +354,197 @@ pub fn run_with_args(cli: Cli) -> Result<()> {
             .read_to_string(&mut source)
             .with_context(|| "Failed to read from stdin")?;
 
-        let mut transformer = Transformer::new(source, &lang_name).with_context(|| {
-            format!(
-                "Failed to initialize transformer for language '{}'",
-                lang_name
-            )
-        })?;
-
-        // Resolve rules for this language
-        let mut rules = Vec::new();
-        // 1. From CLI (priority 0)
-        for (q, t) in cli.query.iter().zip(cli.template.iter()) {
-            rules.push(ResolvedRule {
-                query: q.clone(),
-                template: t.clone(),
-                priority: 0,
-            });
-        }
-        // 2. From RuleFile (matching language)
-        if let Some(ref rf) = rule_file {
-            for r in &rf.rules {
-                if language_matches(&r.language, &lang_name) {
-                    rules.push(ResolvedRule {
-                        query: r.query.clone(),
-                        template: r.template.clone(),
-                        priority: r.priority,
-                    });
-                }
-            }
-        }
-
-        // Sort by priority descending
-        rules.sort_by(|a, b| b.priority.cmp(&a.priority));
-
-        let mut all_modifications = Vec::new();
-        for r in rules {
-            let mut mods = transformer
-                .apply(&r.query, &r.template)
-                .with_context(|| "Failed to apply transformation")?;
-            all_modifications.append(&mut mods);
+        let mut outcome = transform_source(source, &lang_name, &cli, rule_file.as_ref(), None)
+            .with_context(|| format!("Failed to transform stdin as language '{}'", lang_name))?;
+        // Formatters such as prettier pick their parser from the file name
+        let extension = languages::find_spec(&lang_name)
+            .and_then(|spec| spec.file_extension())
+            .unwrap_or(&lang_name);
+        let stdin_path = PathBuf::from(format!("stdin.{}", extension));
+        format_outcome(&mut outcome, &lang_name, &stdin_path, &cli);
+        let would_modify = !outcome.modifications.is_empty();
+        if !cli.json {
+            report_diagnostics("<stdin>", &outcome.diagnostics);
+            report_formatter_error("<stdin>", outcome.formatter_error.as_deref());
         }
 
         if cli.json {
             let output = JsonOutput {
                 status: "success".to_string(),
-                modifications: Some(all_modifications),
+                modifications: Some(outcome.modifications),
+                diagnostics: outcome.diagnostics,
+                formatter_errors: outcome
+                    .formatter_error
+                    .map(|message| FormatterError {
+                        filename: None,
+                        message,
+                    })
+                    .into_iter()
+                    .collect(),
                 error: None,
             };
             println!("{}", serde_json::to_string_pretty(&output)?);
+        } else if cli.check {
+            if would_modify {
+                println!("<stdin>: {}", outcome.changed_rules.join(", "));
+            }
+        } else if cli.diff {
+            print!(
+                "{}",
+                diff::unified_diff(
+                    "<stdin>",
+                    &outcome.original,
+                    &outcome.transformed,
+                    cli.context
+                )
+            );
         } else {
-            print!("{}", transformer.get_source());
+            print!("{}", outcome.transformed);
+        }
+
+        if cli.check && would_modify {
+            std::process::exit(1);
         }
         return Ok(());
     }
 
-    // Parallel processing for files
-    let all_modifications_shared = Arc::new(Mutex::new(Vec::new()));
-    let has_error = Arc::new(Mutex::new(false));
-
-    file_paths.par_iter().for_each(|file_path| {
-        let process_file = || -> Result<()> {
-            let source = fs::read_to_string(file_path)
-                .with_context(|| format!("Failed to read file: {:?}", file_path))?;
-
-            let ext = file_path
-                .extension()
-                .and_then(|e| e.to_str())
-                .ok_or_else(|| anyhow!("Could not detect file extension for {:?}", file_path))?;
-
-            // Prefer explicit language if provided, otherwise detect
-            let lang_name = cli.language.clone().unwrap_or_else(|| ext.to_string());
-
-            let mut transformer = Transformer::new(source, &lang_name).with_context(|| {
-                format!("Failed to initialize transformer for file {:?}", file_path)
-            })?;
-
-            // Resolve rules for this file's language
-            let mut rules = Vec::new();
-            for (q, t) in cli.query.iter().zip(cli.template.iter()) {
-                rules.push(ResolvedRule {
-                    query: q.clone(),
-                    template: t.clone(),
-                    priority: 0,
-                });
-            }
-            if let Some(ref rf) = rule_file {
-                for r in &rf.rules {
-                    // Match language name or extension
-                    if language_matches(&r.language, &lang_name) {
-                        rules.push(ResolvedRule {
-                            query: r.query.clone(),
-                            template: r.template.clone(),
-                            priority: r.priority,
-                        });
-                    }
-                }
-            }
-            rules.sort_by(|a, b| b.priority.cmp(&a.priority));
+    // Transform files in parallel, keeping results in argument order so output is stable.
+    // Interactive review has to ask one question at a time, so it runs sequentially.
+    let outcomes: Vec<(&PathBuf, Result<FileOutcome>)> = if cli.interactive {
+        let mut reviewer =
+            review::Reviewer::new(io::stdin().lock(), io::stderr(), io::stderr().is_terminal());
+        file_paths
+            .iter()
+            .map(|file_path| {
+                let label = file_path.to_string_lossy();
+                let mut review = |rule: &str, source: &str, m: &Modification| {
+                    reviewer.review(&label, rule, source, m)
+                };
+                let outcome = process_file(
+                    file_path,
+                    &cli,
+                    config.as_ref(),
+                    rule_file.as_ref(),
+                    Some(&mut review),
+                );
+                (file_path, outcome)
+            })
+            .collect()
+    } else {
+        file_paths
+            .par_iter()
+            .map(|file_path| {
+                let outcome =
+                    process_file(file_path, &cli, config.as_ref(), rule_file.as_ref(), None);
+                (file_path, outcome)
+            })
+            .collect()
+    };
 
-            let mut file_modifications = Vec::new();
-            for r in rules {
-                let mut mods = transformer.apply(&r.query, &r.template)?;
-                file_modifications.append(&mut mods);
+    let mut all_modifications = Vec::new();
+    let mut all_diagnostics = Vec::new();
+    let mut all_formatter_errors = Vec::new();
+    let mut would_modify = Vec::new();
+    let mut pending_writes = Vec::new();
+    let mut has_error = false;
+
+    for (file_path, outcome) in outcomes {
+        let handle_outcome = |outcome: FileOutcome| -> Result<()> {
+            if !outcome.changed_rules.is_empty() {
+                would_modify.push((file_path, outcome.changed_rules.clone()));
             }
 
             if cli.json {
-                let mut mods = all_modifications_shared.lock().unwrap();
-                for mut m in file_modifications {
+                for mut m in outcome.modifications {
                     m.filename = Some(file_path.to_string_lossy().to_string());
-                    mods.push(m);
+                    all_modifications.push(m);
                 }
-            } else {
-                let new_source = transformer.get_source();
-                if cli.in_place {
-                    fs::write(file_path, new_source)
+                for mut d in outcome.diagnostics {
+                    d.filename = Some(file_path.to_string_lossy().to_string());
+                    all_diagnostics.push(d);
+                }
+                if let Some(message) = outcome.formatter_error {
+                    all_formatter_errors.push(FormatterError {
+                        filename: Some(file_path.to_string_lossy().to_string()),
+                        message,
+                    });
+                }
+                return Ok(());
+            }
+
+            report_diagnostics(&file_path.to_string_lossy(), &outcome.diagnostics);
+            report_formatter_error(
+                &file_path.to_string_lossy(),
+                outcome.formatter_error.as_deref(),
+            );
+            if cli.atomic {
+                pending_writes.push(transaction::PendingWrite {
+                    path: file_path.clone(),
+                    original: outcome.original,
+                    modified: outcome.transformed,
+                });
+            } else if cli.in_place || cli.interactive {
+                // Leave files alone when nothing matched or every change was rejected
+                if outcome.transformed != outcome.original {
+                    fs::write(file_path, &outcome.transformed)
                         .with_context(|| format!("Failed to write to file: {:?}", file_path))?;
-                } else {
-                    let mut stdout = io::stdout().lock();
-                    use std::io::Write;
-                    write!(stdout, "{}", new_source).ok();
                 }
+            } else if cli.diff {
+                print!(
+                    "{}",
+                    diff::unified_diff(
+                        &file_path.to_string_lossy(),
+                        &outcome.original,
+                        &outcome.transformed,
+                        cli.context
+                    )
+                );
+            } else if !cli.check {
+                print!("{}", outcome.transformed);
             }
             Ok(())
         };
 
-        if let Err(e) = process_file() {
+        if let Err(e) = outcome.and_then(handle_outcome) {
             eprintln!("Error processing {:?}: {:?}", file_path, e);
-            let mut err_flag = has_error.lock().unwrap();
-            *err_flag = true;
+            has_error = true;
         }
-    });
+    }
 
-    if cli.json {
-        let error_occurred = *has_error.lock().unwrap();
-        let modifications = all_modifications_shared.lock().unwrap().clone();
+    if cli.atomic {
+        if has_error {
+            eprintln!("Aborted: no files were written because one or more files failed");
+            std::process::exit(1);
+        }
+        let written = transaction::commit(pending_writes, Path::new(transaction::JOURNAL_PATH))?;
+        eprintln!(
+            "Wrote {} file(s); run `graft --undo` to restore them",
+            written
+        );
+    }
+
+    if cli.check && !cli.json {
+        for (file_path, rules) in &would_modify {
+            println!("{}: {}", file_path.display(), rules.join(", "));
+        }
+        if would_modify.is_empty() {
+            println!("No files would be modified");
+        } else {
+            println!("{} file(s) would be modified", would_modify.len());
+        }
+    }
 
+    if cli.json {
         let output = JsonOutput {
-            status: if error_occurred {
+            status: if has_error {
                 "partial_error".to_string()
             } else {
                 "success".to_string()
             },
-            modifications: Some(modifications),
-            error: if error_occurred {
+            modifications: Some(all_modifications),
+            diagnostics: all_diagnostics,
+            formatter_errors: all_formatter_errors,
+            error: if has_error {
                 Some("One or more files failed to process".to_string())
             } else {
                 None
@@ -312,16 +552,330 @@ pub fn run_with_args(cli: Cli) -> Result<()> {
         };
         println!("{}", serde_json::to_string_pretty(&output)?);
 
-        if error_occurred {
+        if has_error {
             std::process::exit(1);
         }
-    } else if *has_error.lock().unwrap() {
+    } else if has_error {
+        std::process::exit(1);
+    }
+
+    if cli.check && !would_modify.is_empty() {
         std::process::exit(1);
     }
 
     Ok(())
 }
 
+/// Loads the closest `graft.toml`, unless disabled with `--no-config`.
+fn load_config(disabled: bool, load_grammars: bool) -> Result<Option<ProjectConfig>> {
+    if disabled {
+        return Ok(None);
+    }
+    let cwd = std::env::current_dir().context("Failed to read the working directory")?;
+    let config = ProjectConfig::discover(&cwd, load_grammars)?;
+    if let Some(config) = config.as_ref().filter(|c| !c.skipped_grammars.is_empty()) {
+        eprintln!(
+            "Warning: {}: not loading grammar(s) {}; pass --load-grammars if you trust this config",
+            config.path.display(),
+            config.skipped_grammars.join(", ")
+        );
+    }
+    Ok(config)
+}
+
+/// Prints a failed `--format` as a warning on stderr.
+fn report_formatter_error(label: &str, error: Option<&str>) {
+    if let Some(error) = error {
+        eprintln!(
+            "Warning: {}: formatting failed, keeping the unformatted rewrite: {}",
+            label, error
+        );
+    }
+}
+
+/// Prints non-fatal diagnostics as warnings on stderr.
+fn report_diagnostics(label: &str, diagnostics: &[Diagnostic]) {
+    for d in diagnostics {
+        eprintln!(
+            "Warning: {}:{} ({}): {}",
+            label,
+            d.start_position.row + 1,
+            d.rule.as_deref().unwrap_or("unnamed rule"),
+            d.message
+        );
+    }
+}
+
+/// Collects the rules applicable to `lang_name`, sorted by priority (highest first).
+/// CLI queries have priority 0 and do not apply to `embedded` code.
+fn resolve_rules(
+    cli: &Cli,
+    rule_file: Option<&RuleFile>,
+    lang_name: &str,
+    embedded: bool,
+) -> Vec<ResolvedRule> {
+    let mut rules = Vec::new();
+    let queries = if embedded { &[][..] } else { &cli.query[..] };
+    for (i, (q, t)) in queries.iter().zip(cli.template.iter()).enumerate() {
+        rules.push(ResolvedRule {
+            name: format!("query #{}", i + 1),
+            query: q.clone(),
+            template: t.clone(),
+            priority: 0,
+            options: ApplyOptions {
+                on_error: cli.on_error,
+                allow_existing_errors: cli.allow_existing_errors,
+                overlap: cli.overlap,
+                action: cli.action,
+                repeat: cli.repeat,
+                ..ApplyOptions::default()
+            },
+        });
+    }
+    if let Some(rf) = rule_file {
+        for (i, r) in rf.rules.iter().enumerate() {
+            // Match language name or extension
+            if language_matches(&r.language, lang_name) {
+                let (template, target_templates) = r.template.split();
+                rules.push(ResolvedRule {
+                    name: r.name.clone().unwrap_or_else(|| format!("rule #{}", i + 1)),
+                    query: r.query.clone(),
+                    template,
+                    priority: r.priority,
+                    options: ApplyOptions {
+                        on_error: r.on_error.unwrap_or(cli.on_error),
+                        allow_existing_errors: cli.allow_existing_errors,
+                        overlap: r.overlap.unwrap_or(cli.overlap),
+                        action: r.action.unwrap_or(cli.action),
+                        repeat: r.repeat.map_or(cli.repeat, Repeat::max_iterations),
+                        constraints: r.constraints.clone(),
+                        target_templates,
+                    },
+                });
+            }
+        }
+    }
+    rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    rules
+}
+
+/// Runs every applicable rule against a single file, without writing anything.
+fn process_file(
+    file_path: &PathBuf,
+    cli: &Cli,
+    config: Option<&ProjectConfig>,
+    rule_file: Option<&RuleFile>,
+    review: Option<&mut ReviewFn>,
+) -> Result<FileOutcome> {
+    let source = fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read file: {:?}", file_path))?;
+
+    // Prefer explicit language if provided, then the project config, then detection
+    let lang_name = match &cli.language {
+        Some(lang) => lang.clone(),
+        None => config::detect(config, file_path, &source)
+            .ok_or_else(|| {
+                // Name the missing feature for files of a language left out of this build
+                match languages::detect_disabled(file_path, &source) {
+                    Some(lang) => languages::unsupported(lang),
+                    None => anyhow!(
+                        "Could not detect the language of {:?}. Use --language to set it.",
+                        file_path
+                    ),
+                }
+            })?
+            .name
+            .to_string(),
+    };
+
+    let mut outcome = transform_source(source, &lang_name, cli, rule_file, review)
+        .with_context(|| format!("Failed to transform file {:?}", file_path))?;
+    format_outcome(&mut outcome, &lang_name, file_path, cli);
+    Ok(outcome)
+}
+
+/// Runs `--format` on a rewritten file, keeping it unformatted if the formatter fails.
+fn format_outcome(outcome: &mut FileOutcome, lang_name: &str, path: &Path, cli: &Cli) {
+    let Some(formatter) = &cli.format else {
+        return;
+    };
+    if outcome.transformed == outcome.original {
+        return;
+    }
+    match format::format(
+        &outcome.original,
+        &outcome.transformed,
+        lang_name,
+        path,
+        formatter,
+        cli.format_scope,
+    ) {
+        Ok(formatted) => outcome.transformed = formatted,
+        Err(e) => outcome.formatter_error = Some(format!("{:#}", e)),
+    }
+}
+
+/// Applies every rule matching `lang_name` to `source` and records what changed, then the
+/// rule file's rules for other languages to the code embedded in it.
+/// When `review` is given, it decides about each match before it is applied.
+fn transform_source(
+    source: String,
+    lang_name: &str,
+    cli: &Cli,
+    rule_file: Option<&RuleFile>,
+    mut review: Option<&mut ReviewFn>,
+) -> Result<FileOutcome> {
+    let mut transformer = Transformer::new(source.clone(), lang_name).with_context(|| {
+        format!(
+            "Failed to initialize transformer for language '{}'",
+            lang_name
+        )
+    })?;
+
+    let mut outcome = FileOutcome {
+        original: source,
+        transformed: String::new(),
+        modifications: Vec::new(),
+        changed_rules: Vec::new(),
+        diagnostics: Vec::new(),
+        formatter_error: None,
+    };
+    let rules = resolve_rules(cli, rule_file, lang_name, false);
+    apply_rules(&mut transformer, &rules, &mut review, &mut outcome)?;
+    outcome.transformed = transformer.get_source().to_string();
+
+    if let Some(rule_file) = rule_file {
+        transform_embedded(&mut outcome, lang_name, cli, rule_file, &mut review)?;
+    }
+    Ok(outcome)
+}
+
+/// Applies `rules` in order, adding their modifications and diagnostics to `outcome`.
+fn apply_rules(
+    transformer: &mut Transformer,
+    rules: &[ResolvedRule],
+    review: &mut Option<&mut ReviewFn>,
+    outcome: &mut FileOutcome,
+) -> Result<()> {
+    for r in rules {
+        let mods = transformer
+            .apply_with(
+                &r.query,
+                &r.template,
+                &r.options,
+                |source, m| match review.as_mut() {
+                    Some(review) => review(&r.name, source, m),
+                    None => true,
+                },
+            )
+            .with_context(|| format!("Failed to apply rule '{}'", r.name))?;
+        if !mods.is_empty() && !outcome.changed_rules.contains(&r.name) {
+            outcome.changed_rules.push(r.name.clone());
+        }
+        outcome.modifications.extend(mods.into_iter().map(|mut m| {
+            m.rule = Some(r.name.clone());
+            m
+        }));
+        outcome
+            .diagnostics
+            .extend(transformer.take_diagnostics().into_iter().map(|mut d| {
+                d.rule = Some(r.name.clone());
+                d
+            }));
+    }
+    Ok(())
+}
+
+/// Applies the rule file's rules to code embedded in `outcome.transformed`, such as Bash in a
+/// YAML `run:` key, one piece of embedded code at a time. Code that fails to transform is left
+/// as it was, with a warning.
+fn transform_embedded(
+    outcome: &mut FileOutcome,
+    host_name: &str,
+    cli: &Cli,
+    rule_file: &RuleFile,
+    review: &mut Option<&mut ReviewFn>,
+) -> Result<()> {
+    let host = languages::find_spec(host_name).ok_or_else(|| languages::unsupported(host_name))?;
+    // Only look for embedded code when some rule is written for another language
+    if rule_file
+        .rules
+        .iter()
+        .all(|r| language_matches(&r.language, host.name))
+    {
+        return Ok(());
+    }
+
+    let mut embedded = injections::find(&outcome.transformed, host)?;
+    for i in 0..embedded.len() {
+        let language = embedded[i].language;
+        let rules = resolve_rules(cli, Some(rule_file), language.name, true);
+        if language.name == host.name || rules.is_empty() {
+            continue;
+        }
+        let mut applied = FileOutcome {
+            original: String::new(),
+            transformed: String::new(),
+            modifications: Vec::new(),
+            changed_rules: Vec::new(),
+            diagnostics: Vec::new(),
+            formatter_error: None,
+        };
+        let result = Transformer::new_embedded(
+            outcome.transformed.clone(),
+            language.name,
+            embedded[i].ranges.clone(),
+        )
+        .and_then(|mut transformer| {
+            apply_rules(&mut transformer, &rules, review, &mut applied)?;
+            Ok(transformer.get_source().to_string())
+        });
+        let transformed = match result {
+            Ok(transformed) => transformed,
+            Err(e) => {
+                let start_byte = embedded[i].ranges[0].start;
+                let before = &outcome.transformed[..start_byte];
+                let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+                outcome.diagnostics.push(Diagnostic {
+                    filename: None,
+                    rule: None,
+                    start_byte,
+                    start_position: SerializablePoint {
+                        row: before.matches('\n').count(),
+                        column: start_byte - line_start,
+                    },
+                    message: format!(
+                        "Skipped embedded {} code because a rule failed: {:#}",
+                        language.name, e
+                    ),
+                });
+                continue;
+            }
+        };
+
+        // Later embedded code moves with the edits before it
+        for m in &applied.modifications {
+            for other in &mut embedded[i + 1..] {
+                injections::shift_ranges(
+                    &mut other.ranges,
+                    m.start_byte,
+                    m.old_end_byte,
+                    m.new_end_byte,
+                );
+            }
+        }
+        outcome.transformed = transformed;
+        outcome.modifications.extend(applied.modifications);
+        outcome.diagnostics.extend(applied.diagnostics);
+        for name in applied.changed_rules {
+            if !outcome.changed_rules.contains(&name) {
+                outcome.changed_rules.push(name);
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -334,4 +888,35 @@ mod tests {
         assert_eq!(cli.query, vec!["query".to_string()]);
         assert_eq!(cli.template, vec!["template".to_string()]);
     }
+
+    #[test]
+    fn test_cli_diff_conflicts_with_in_place() {
+        let args = vec!["graft", "file.rs", "-q", "q", "-t", "t", "--diff", "-i"];
+        assert!(Cli::try_parse_from(args).is_err());
+
+        let args = vec![
+            "graft", "file.rs", "-q", "q", "-t", "t", "--diff", "-U", "1",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(cli.diff);
+        assert_eq!(cli.context, 1);
+    }
+
+    #[test]
+    fn test_cli_rename_subcommand() {
+        let args = vec![
+            "graft", "rename", "old", "new", "src/", "--kind", "type", "-l", "rust",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Some(Command::Rename(rename)) = cli.command else {
+            panic!("expected the rename subcommand");
+        };
+        assert_eq!(rename.kind, crate::graft::rename::SymbolKind::Type);
+        assert_eq!(rename.files, vec!["src/".to_string()]);
+
+        let args = vec![
+            "graft", "rename", "old", "new", "src/", "-k", "variable", "-l", "rust",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
 }
@@ -0,0 +1,127 @@
+use super::{diff, transaction, walk};
+use crate::graft::config::{self, ProjectConfig};
+use crate::graft::languages;
+use crate::graft::rename::{Renamer, SymbolKind};
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use rayon::prelude::*;
+use std::fs;
+use std::path::Path;
+
+#[derive(Args, Debug)]
+pub struct RenameArgs {
+    /// Current name of the symbol.
+    pub old: String,
+
+    /// New name of the symbol.
+    pub new: String,
+
+    /// Source file(s), director(ies) or glob pattern(s) to rename the symbol in.
+    #[arg(required = true)]
+    pub files: Vec<String>,
+
+    /// Kind of symbol to rename: `function`, `type` or `field`.
+    #[arg(short, long, value_name = "KIND")]
+    pub kind: SymbolKind,
+
+    /// Language of the files. Only files of this language are renamed.
+    #[arg(short, long, value_name = "LANG")]
+    pub language: String,
+
+    /// Write the renamed files in place, all or none of them. Prints a diff otherwise.
+    #[arg(short, long)]
+    pub in_place: bool,
+
+    /// Skip files matching this glob. Can be specified multiple times.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+/// Renames the symbol in every file, or in none of them if any conflict is found.
+pub fn run(args: &RenameArgs, config: Option<&ProjectConfig>) -> Result<()> {
+    let renamer = Renamer::new(&args.language, args.kind, &args.old, &args.new)?;
+    let language = languages::find_spec(&args.language)
+        .ok_or_else(|| languages::unsupported(&args.language))?;
+
+    if args.in_place {
+        // Like `--atomic`, even a rename that ends up writing nothing replaces the previous
+        // run's journal
+        transaction::clear(Path::new(transaction::JOURNAL_PATH))?;
+    }
+
+    let file_paths = walk::collect_files(&args.files, &[], &args.exclude, config)?;
+    let analyses = file_paths
+        .par_iter()
+        .map(|path| {
+            let source = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read file: {:?}", path))?;
+            if config::detect(config, path, &source).is_none_or(|spec| spec.name != language.name) {
+                return Ok(None);
+            }
+            let analysis = renamer
+                .analyze(&source)
+                .with_context(|| format!("Failed to analyze file {:?}", path))?;
+            Ok(Some((path, source, analysis)))
+        })
+        .filter_map(Result::transpose)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut conflicts = 0;
+    for (path, _, analysis) in &analyses {
+        for c in &analysis.conflicts {
+            eprintln!(
+                "Conflict: {}:{}: {}",
+                path.display(),
+                c.position.row + 1,
+                c.message
+            );
+            conflicts += 1;
+        }
+    }
+    if conflicts > 0 {
+        return Err(anyhow!(
+            "Rename aborted because of {} conflict(s); no files were changed",
+            conflicts
+        ));
+    }
+    if !analyses.iter().any(|(_, _, a)| a.defines_symbol()) {
+        return Err(anyhow!(
+            "No {} named '{}' is defined in the given files",
+            args.kind,
+            args.old
+        ));
+    }
+
+    let mut pending_writes = Vec::new();
+    let mut occurrences = 0;
+    for (path, source, analysis) in analyses {
+        if analysis.occurrences.is_empty() {
+            continue;
+        }
+        let (renamed, modifications) = renamer
+            .apply(source.clone(), &analysis)
+            .with_context(|| format!("Failed to rename in file {:?}", path))?;
+        occurrences += modifications.len();
+        if args.in_place {
+            pending_writes.push(transaction::PendingWrite {
+                path: path.clone(),
+                original: source,
+                modified: renamed,
+            });
+        } else {
+            print!(
+                "{}",
+                diff::unified_diff(&path.to_string_lossy(), &source, &renamed, 3)
+            );
+        }
+    }
+
+    if args.in_place {
+        let written = transaction::commit(pending_writes, Path::new(transaction::JOURNAL_PATH))?;
+        eprintln!(
+            "Renamed {} occurrence(s) in {} file(s); run `graft --undo` to restore them",
+            occurrences, written
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use crate::graft::Modification;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Asks the user about every match before it is applied (`--interactive`).
+pub struct Reviewer<R, W> {
+    input: R,
+    output: W,
+    color: bool,
+    /// Rules whose remaining matches were all accepted with `a`.
+    accepted_rules: HashSet<String>,
+    quit: bool,
+}
+
+impl<R: BufRead, W: Write> Reviewer<R, W> {
+    pub fn new(input: R, output: W, color: bool) -> Self {
+        Self {
+            input,
+            output,
+            color,
+            accepted_rules: HashSet::new(),
+            quit: false,
+        }
+    }
+
+    /// Shows the edit as a before/after hunk and returns whether it should be applied.
+    pub fn review(&mut self, file: &str, rule: &str, source: &str, m: &Modification) -> bool {
+        if self.quit {
+            return false;
+        }
+        if self.accepted_rules.contains(rule) {
+            return true;
+        }
+
+        self.print_hunk(file, rule, source, m);
+        loop {
+            write!(
+                self.output,
+                "Apply this change? [y]es, [n]o, [a]ll for this rule, [q]uit: "
+            )
+            .ok();
+            self.output.flush().ok();
+
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer).unwrap_or(0) == 0 {
+                // Treat a closed input like quitting so nothing is applied unasked
+                self.quit = true;
+                return false;
+            }
+            match answer.trim() {
+                "y" | "yes" => return true,
+                "n" | "no" => return false,
+                "a" | "all" => {
+                    self.accepted_rules.insert(rule.to_string());
+                    return true;
+                }
+                "q" | "quit" => {
+                    self.quit = true;
+                    return false;
+                }
+                _ => writeln!(self.output, "Please answer y, n, a or q.").ok(),
+            };
+        }
+    }
+
+    fn print_hunk(&mut self, file: &str, rule: &str, source: &str, m: &Modification) {
+        let line_start = source[..m.start_byte].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[m.old_end_byte..]
+            .find('\n')
+            .map_or(source.len(), |i| i + m.old_end_byte);
+        let before = &source[line_start..line_end];
+        let after = format!(
+            "{}{}{}",
+            &source[line_start..m.start_byte],
+            m.replacement,
+            &source[m.old_end_byte..line_end]
+        );
+
+        let (red, green, bold, reset) = if self.color {
+            (RED, GREEN, BOLD, RESET)
+        } else {
+            ("", "", "", "")
+        };
+
+        writeln!(
+            self.output,
+            "{}{}:{} ({}){}",
+            bold,
+            file,
+            m.start_position.row + 1,
+            rule,
+            reset
+        )
+        .ok();
+        for line in before.lines() {
+            writeln!(self.output, "{}-{}{}", red, line, reset).ok();
+        }
+        for line in after.lines() {
+            writeln!(self.output, "{}+{}{}", green, line, reset).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graft::SerializablePoint;
+    use std::io::Cursor;
+
+    fn modification(start_byte: usize, old_end_byte: usize, replacement: &str) -> Modification {
+        let point = SerializablePoint { row: 0, column: 0 };
+        Modification {
+            filename: None,
+            rule: None,
+            start_byte,
+            old_end_byte,
+            new_end_byte: start_byte + replacement.len(),
+            start_position: point,
+            old_end_position: point,
+            new_end_position: point,
+            replacement: replacement.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_review_answers() {
+        let source = "let x = a + b;";
+        let m = modification(8, 13, "add(a, b)");
+        let mut output = Vec::new();
+        let mut reviewer = Reviewer::new(Cursor::new("n\nwhat\na\n"), &mut output, false);
+
+        assert!(!reviewer.review("main.rs", "r1", source, &m));
+        assert!(reviewer.review("main.rs", "r1", source, &m));
+        // `a` accepts the remaining matches of the rule without asking again
+        assert!(reviewer.review("main.rs", "r1", source, &m));
+        // Input is exhausted, which behaves like quitting
+        assert!(!reviewer.review("main.rs", "r2", source, &m));
+        assert!(!reviewer.review("main.rs", "r1", source, &m));
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("main.rs:1 (r1)\n-let x = a + b;\n+let x = add(a, b);\n"));
+        assert!(output.contains("Please answer y, n, a or q."));
+    }
+
+    #[test]
+    fn test_review_quit_rejects_everything_after() {
+        let m = modification(0, 1, "b");
+        let mut reviewer = Reviewer::new(Cursor::new("q\n"), Vec::new(), false);
+
+        assert!(!reviewer.review("main.rs", "r1", "a", &m));
+        assert!(!reviewer.review("other.rs", "r2", "a", &m));
+    }
+}
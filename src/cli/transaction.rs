@@ -0,0 +1,237 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Where the journal of the last `--atomic` run is kept, relative to the working directory.
+pub const JOURNAL_PATH: &str = ".graft/journal.json";
+
+/// A file whose new contents are ready to be written.
+pub struct PendingWrite {
+    pub path: PathBuf,
+    pub original: String,
+    pub modified: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    entries: Vec<JournalEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    path: PathBuf,
+    original: String,
+    modified: String,
+}
+
+/// Writes every changed file or none of them.
+///
+/// The journal is recorded first, new contents are staged in temporary files next to their
+/// targets, and only then renamed into place. If a rename fails, files that were already
+/// replaced are restored. Returns the number of files written.
+pub fn commit(writes: Vec<PendingWrite>, journal_path: &Path) -> Result<usize> {
+    let mut entries = Vec::new();
+    for w in writes {
+        if w.original == w.modified {
+            continue;
+        }
+        let path = fs::canonicalize(&w.path)
+            .with_context(|| format!("Failed to resolve path: {:?}", w.path))?;
+        entries.push(JournalEntry {
+            path,
+            original: w.original,
+            modified: w.modified,
+        });
+    }
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let journal = Journal { entries };
+    if let Some(dir) = journal_path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create journal directory: {:?}", dir))?;
+    }
+    write_atomic(journal_path, &serde_json::to_string_pretty(&journal)?)
+        .context("Failed to write transaction journal")?;
+
+    // Stage everything before touching any target, so a failure here leaves the tree as it was
+    let mut staged = Vec::new();
+    for entry in &journal.entries {
+        staged.push(stage(&entry.path, &entry.modified)?);
+    }
+
+    for (i, temp) in staged.into_iter().enumerate() {
+        let target = &journal.entries[i].path;
+        if let Err(e) = temp.persist(target) {
+            let mut message = format!("Failed to replace {:?}: {}", target, e.error);
+            for done in &journal.entries[..i] {
+                if let Err(e) = write_atomic(&done.path, &done.original) {
+                    message.push_str(&format!("\nFailed to roll back {:?}: {}", done.path, e));
+                }
+            }
+            return Err(anyhow!(message));
+        }
+    }
+
+    Ok(journal.entries.len())
+}
+
+/// Removes the journal of the previous transaction, so `--undo` never restores files from a
+/// run older than the last one.
+pub fn clear(journal_path: &Path) -> Result<()> {
+    match fs::remove_file(journal_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove journal: {:?}", journal_path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Restores the files recorded by the last transaction and removes its journal.
+///
+/// Refuses to touch anything if one of the files changed since the transaction wrote it.
+/// Returns the number of files restored.
+pub fn undo(journal_path: &Path) -> Result<usize> {
+    let content = fs::read_to_string(journal_path).with_context(|| {
+        format!(
+            "No transaction to undo: failed to read journal {:?}",
+            journal_path
+        )
+    })?;
+    let journal: Journal = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse journal: {:?}", journal_path))?;
+
+    let conflicts: Vec<_> = journal
+        .entries
+        .iter()
+        .filter(|e| fs::read_to_string(&e.path).ok().as_deref() != Some(e.modified.as_str()))
+        .map(|e| format!("{:?}", e.path))
+        .collect();
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "Refusing to undo: these files changed after graft wrote them:\n{}",
+            conflicts.join("\n")
+        ));
+    }
+
+    for entry in &journal.entries {
+        write_atomic(&entry.path, &entry.original)
+            .with_context(|| format!("Failed to restore {:?}", entry.path))?;
+    }
+    fs::remove_file(journal_path)
+        .with_context(|| format!("Failed to remove journal: {:?}", journal_path))?;
+
+    Ok(journal.entries.len())
+}
+
+/// Writes `content` to a temporary file next to `path`, keeping the permissions of `path`.
+fn stage(path: &Path, content: &str) -> Result<NamedTempFile> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp = NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+    temp.write_all(content.as_bytes())
+        .with_context(|| format!("Failed to stage new contents for {:?}", path))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(temp.path(), metadata.permissions()).ok();
+    }
+    Ok(temp)
+}
+
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    stage(path, content)?
+        .persist(path)
+        .map_err(|e| anyhow!("Failed to replace {:?}: {}", path, e.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_commit_and_undo() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.rs");
+        let b = dir.path().join("b.rs");
+        let journal = dir.path().join(".graft/journal.json");
+        fs::write(&a, "a")?;
+        fs::write(&b, "b")?;
+
+        let written = commit(
+            vec![
+                PendingWrite {
+                    path: a.clone(),
+                    original: "a".to_string(),
+                    modified: "A".to_string(),
+                },
+                PendingWrite {
+                    path: b.clone(),
+                    original: "b".to_string(),
+                    modified: "b".to_string(),
+                },
+            ],
+            &journal,
+        )?;
+        assert_eq!(written, 1);
+        assert_eq!(fs::read_to_string(&a)?, "A");
+        assert!(journal.exists());
+
+        assert_eq!(undo(&journal)?, 1);
+        assert_eq!(fs::read_to_string(&a)?, "a");
+        assert!(!journal.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_refuses_when_file_changed() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.rs");
+        let journal = dir.path().join("journal.json");
+        fs::write(&a, "a")?;
+
+        commit(
+            vec![PendingWrite {
+                path: a.clone(),
+                original: "a".to_string(),
+                modified: "A".to_string(),
+            }],
+            &journal,
+        )?;
+        fs::write(&a, "edited by hand")?;
+
+        assert!(undo(&journal).is_err());
+        assert_eq!(fs::read_to_string(&a)?, "edited by hand");
+        assert!(journal.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_forgets_previous_transaction() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.rs");
+        let journal = dir.path().join("journal.json");
+        fs::write(&a, "a")?;
+
+        commit(
+            vec![PendingWrite {
+                path: a.clone(),
+                original: "a".to_string(),
+                modified: "A".to_string(),
+            }],
+            &journal,
+        )?;
+        clear(&journal)?;
+        // A run that changes nothing leaves no journal behind
+        assert_eq!(commit(Vec::new(), &journal)?, 0);
+        clear(&journal)?;
+
+        assert!(undo(&journal).is_err());
+        assert_eq!(fs::read_to_string(&a)?, "A");
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::graft::config::{self, ProjectConfig};
+use anyhow::{Context, Result};
+use glob::{Pattern, glob};
+use ignore::WalkBuilder;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Number of leading bytes inspected when deciding whether a file is binary, and which
+/// language it is in.
+const HEAD_LEN: usize = 8192;
+
+/// Expands file arguments into the list of files to transform.
+///
+/// Globs are expanded and directories are walked recursively, honoring `.gitignore`,
+/// `.ignore` and hidden-file rules. Walked files are kept if they match an `include`
+/// pattern (or, when none are given, are in a supported language) and are not binary.
+/// `exclude` patterns, and those of the project config, apply to every collected path.
+pub fn collect_files(
+    inputs: &[String],
+    include: &[String],
+    exclude: &[String],
+    config: Option<&ProjectConfig>,
+) -> Result<Vec<PathBuf>> {
+    let include = compile_patterns(include)?;
+    let exclude = compile_patterns(exclude)?;
+
+    let mut files = Vec::new();
+    for input in inputs {
+        let entries =
+            glob(input).with_context(|| format!("Failed to read glob pattern: {}", input))?;
+        for entry in entries {
+            match entry {
+                Ok(path) if path.is_dir() => {
+                    walk_dir(&path, &include, &exclude, config, &mut files)
+                }
+                Ok(path) => {
+                    if !matches_any(&exclude, &path, None)
+                        && !config.is_some_and(|c| c.is_excluded(&path))
+                    {
+                        files.push(path);
+                    }
+                }
+                Err(e) => eprintln!("Warning: failed to read glob entry: {}", e),
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn walk_dir(
+    root: &Path,
+    include: &[Pattern],
+    exclude: &[Pattern],
+    config: Option<&ProjectConfig>,
+    files: &mut Vec<PathBuf>,
+) {
+    let walker = WalkBuilder::new(root)
+        // Honor .gitignore even outside of a git checkout
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Warning: failed to walk {:?}: {}", root, e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+
+        let path = entry.into_path();
+        if matches_any(exclude, &path, Some(root)) || config.is_some_and(|c| c.is_excluded(&path)) {
+            continue;
+        }
+        let head = read_head(&path);
+        // Like git, treat a file as binary if a NUL byte appears near its start
+        if head.contains(&0) {
+            continue;
+        }
+        let wanted = if include.is_empty() {
+            config::detect(config, &path, &String::from_utf8_lossy(&head)).is_some()
+        } else {
+            matches_any(include, &path, Some(root))
+        };
+        if wanted {
+            files.push(path);
+        }
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).with_context(|| format!("Invalid glob pattern: {}", p)))
+        .collect()
+}
+
+/// Matches `path` as given, relative to the walk `root`, and by file name alone.
+fn matches_any(patterns: &[Pattern], path: &Path, root: Option<&Path>) -> bool {
+    let relative = root.and_then(|r| path.strip_prefix(r).ok());
+    let file_name = path.file_name().map(Path::new);
+    patterns.iter().any(|p| {
+        p.matches_path(path)
+            || relative.is_some_and(|r| p.matches_path(r))
+            || file_name.is_some_and(|f| p.matches_path(f))
+    })
+}
+
+/// Reads the first bytes of a file, enough to spot binary files, `#!` lines and modelines.
+fn read_head(path: &Path) -> Vec<u8> {
+    let mut buf = vec![0u8; HEAD_LEN];
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let n = file.read(&mut buf).unwrap_or(0);
+    buf.truncate(n);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    #[cfg(all(feature = "lang-rust", feature = "lang-make", feature = "lang-python"))]
+    fn test_walk_honors_gitignore_and_extensions() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested"))?;
+        fs::create_dir_all(root.join("target"))?;
+        fs::create_dir_all(root.join(".hidden"))?;
+        fs::write(root.join(".gitignore"), "target/\n")?;
+        fs::write(root.join("src/main.rs"), "fn main() {}")?;
+        fs::write(root.join("src/nested/lib.rs"), "fn lib() {}")?;
+        fs::write(root.join("src/notes.unknown"), "text")?;
+        fs::write(root.join("src/Makefile"), "all:\n")?;
+        fs::write(root.join("src/deploy"), "#!/usr/bin/env python3\n")?;
+        fs::write(root.join("src/blob.rs"), b"\0\x01\x02")?;
+        fs::write(root.join("target/gen.rs"), "fn gen() {}")?;
+        fs::write(root.join(".hidden/secret.rs"), "fn secret() {}")?;
+
+        let files = collect_files(&[root.to_string_lossy().to_string()], &[], &[], None)?;
+        let mut relative: Vec<_> = files
+            .iter()
+            .map(|p| p.strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+        relative.sort();
+
+        assert_eq!(
+            relative,
+            vec![
+                PathBuf::from("src/Makefile"),
+                PathBuf::from("src/deploy"),
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("src/nested/lib.rs")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_include_exclude() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/generated"))?;
+        fs::write(root.join("src/main.rs"), "fn main() {}")?;
+        fs::write(root.join("src/generated/api.rs"), "fn api() {}")?;
+        fs::write(root.join("src/script.py"), "print(1)")?;
+
+        let files = collect_files(
+            &[root.to_string_lossy().to_string()],
+            &["*.rs".to_string()],
+            &["src/generated/**".to_string()],
+            None,
+        )?;
+
+        assert_eq!(files, vec![root.join("src/main.rs")]);
+        Ok(())
+    }
+}
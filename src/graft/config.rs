@@ -0,0 +1,308 @@
+use super::languages::{self, LanguageSpec};
+use super::loader;
+use anyhow::{Context, Result, anyhow};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the project config file, looked up in the working directory and its ancestors, up to
+/// the root of the repository.
+pub const CONFIG_FILE_NAME: &str = "graft.toml";
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    rule_files: Vec<PathBuf>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    languages: BTreeMap<String, String>,
+    #[serde(default)]
+    grammars: BTreeMap<String, GrammarConfig>,
+}
+
+/// A grammar loaded at runtime, declared under `[grammars.<name>]`. Paths are relative to the
+/// config's directory.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct GrammarConfig {
+    /// A compiled `.so`/`.dylib`/`.dll`.
+    path: Option<PathBuf>,
+    /// A grammar checkout with `src/parser.c`, compiled on first use.
+    source: Option<PathBuf>,
+    /// Exported language function; defaults to `tree_sitter_<name>`.
+    symbol: Option<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+}
+
+/// Project-wide settings from a `graft.toml`.
+///
+/// Globs without a `/` match file names anywhere in the project; globs with a `/` match paths
+/// relative to the directory containing `graft.toml`, where `*` does not cross directories.
+pub struct ProjectConfig {
+    /// Path of the `graft.toml` file.
+    pub path: PathBuf,
+    /// Grammars declared in the config that were not loaded, since loading was not allowed.
+    pub skipped_grammars: Vec<String>,
+    /// Rule files used when neither `--rule-file` nor `--query` is given, resolved against the
+    /// config's directory.
+    pub rule_files: Vec<PathBuf>,
+    root: PathBuf,
+    /// Working directory, used to resolve relative file paths.
+    cwd: PathBuf,
+    exclude: Vec<Pattern>,
+    /// Most specific first: path globs before file name globs, then longer globs first.
+    languages: Vec<(Pattern, &'static LanguageSpec)>,
+}
+
+impl ProjectConfig {
+    /// Finds the closest `graft.toml` in `start` or one of its ancestors and loads it. The
+    /// search stops at the root of the repository, the first directory containing `.git`, so a
+    /// checkout never picks up a config from outside of it.
+    pub fn discover(start: &Path, load_grammars: bool) -> Result<Option<Self>> {
+        for dir in start.ancestors() {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() {
+                return Self::load(&path, load_grammars).map(Some);
+            }
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Loads the config at `path`. Its `[grammars]` run native code, so they are only compiled
+    /// and loaded if `load_grammars` is set; otherwise they are listed in `skipped_grammars`.
+    pub fn load(path: &Path, load_grammars: bool) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {:?}", path))?;
+        let file: ConfigFile = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file: {:?}", path))?;
+
+        let cwd = std::env::current_dir().context("Failed to read the working directory")?;
+        let root = normalize(&cwd.join(path.parent().unwrap_or(Path::new(""))));
+        let mut skipped_grammars = Vec::new();
+        for (name, grammar) in &file.grammars {
+            if !load_grammars {
+                skipped_grammars.push(name.clone());
+                continue;
+            }
+            register_grammar(name, grammar, &root)
+                .with_context(|| format!("Failed to load grammar '{}' from {:?}", name, path))?;
+        }
+
+        let compile = |glob: &str| {
+            Pattern::new(glob)
+                .with_context(|| format!("Invalid glob pattern '{}' in {:?}", glob, path))
+        };
+
+        let mut languages = file
+            .languages
+            .iter()
+            .filter_map(|(glob, lang)| {
+                let spec = match languages::find_spec(lang) {
+                    Some(spec) => spec,
+                    // Files of a skipped grammar fall back to detection
+                    None if skipped_grammars.contains(lang) => return None,
+                    None => {
+                        return Some(Err(languages::unsupported(lang)).with_context(|| {
+                            format!("Unknown language '{}' for '{}' in {:?}", lang, glob, path)
+                        }));
+                    }
+                };
+                Some(compile(glob).map(|pattern| (pattern, spec)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        languages
+            .sort_by_key(|(p, _)| std::cmp::Reverse((p.as_str().contains('/'), p.as_str().len())));
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            skipped_grammars,
+            rule_files: file.rule_files.iter().map(|f| root.join(f)).collect(),
+            exclude: file
+                .exclude
+                .iter()
+                .map(|g| compile(g))
+                .collect::<Result<_>>()?,
+            languages,
+            root,
+            cwd,
+        })
+    }
+
+    /// The language a glob in `[languages]` assigns to `path`, if any.
+    pub fn language_for(&self, path: &Path) -> Option<&'static LanguageSpec> {
+        self.languages
+            .iter()
+            .find(|(p, _)| self.matches(p, path))
+            .map(|(_, spec)| *spec)
+    }
+
+    /// Whether `path` matches one of the `exclude` globs.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|p| self.matches(p, path))
+    }
+
+    fn matches(&self, pattern: &Pattern, path: &Path) -> bool {
+        if !pattern.as_str().contains('/') {
+            return path
+                .file_name()
+                .is_some_and(|name| pattern.matches_path(Path::new(name)));
+        }
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        normalize(&self.cwd.join(path))
+            .strip_prefix(&self.root)
+            .is_ok_and(|relative| pattern.matches_path_with(relative, options))
+    }
+}
+
+/// Detects the language of a file, letting the project config override
+/// [`languages::detect`].
+pub fn detect(
+    config: Option<&ProjectConfig>,
+    path: &Path,
+    content: &str,
+) -> Option<&'static LanguageSpec> {
+    config
+        .and_then(|c| c.language_for(path))
+        .or_else(|| languages::detect(path, content))
+}
+
+/// Loads a grammar declared in the config and registers it as a language.
+fn register_grammar(name: &str, grammar: &GrammarConfig, root: &Path) -> Result<()> {
+    let symbol = grammar
+        .symbol
+        .clone()
+        .unwrap_or_else(|| loader::default_symbol(name));
+    let library = match (&grammar.path, &grammar.source) {
+        (Some(path), None) => root.join(path),
+        (None, Some(source)) => loader::build_from_source(name, &root.join(source))?,
+        _ => return Err(anyhow!("Set exactly one of 'path' and 'source'")),
+    };
+    let language_fn = loader::load_shared(&library, &symbol)?;
+    // Registered languages live for the rest of the process, like the bundled ones
+    let leak = |s: &str| -> &'static str { Box::leak(s.into()) };
+    let extensions: Vec<&'static str> = grammar.extensions.iter().map(|e| leak(e)).collect();
+    languages::register(LanguageSpec {
+        name: leak(name),
+        extensions: Box::leak(extensions.into_boxed_slice()),
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: language_fn,
+        locals: &[],
+        injections: &[],
+    });
+    Ok(())
+}
+
+/// Resolves `.` and `..` without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-cpp",
+        feature = "lang-html",
+        feature = "lang-javascript"
+    ))]
+    fn test_discover_and_language_overrides() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested"))?;
+        fs::write(
+            root.join(CONFIG_FILE_NAME),
+            r#"
+rule_files = ["rules/cleanup.toml"]
+exclude = ["vendor/**"]
+
+[languages]
+"*.h" = "cpp"
+"*.html" = "javascript"
+"templates/**/*.html" = "html"
+"#,
+        )?;
+
+        let config = ProjectConfig::discover(&root.join("src/nested"), false)?.unwrap();
+        assert_eq!(config.rule_files, vec![root.join("rules/cleanup.toml")]);
+
+        let lang = |p: &str| config.language_for(&root.join(p)).map(|s| s.name);
+        assert_eq!(lang("src/nested/api.h"), Some("C++"));
+        assert_eq!(lang("templates/mail/index.html"), Some("HTML"));
+        assert_eq!(lang("src/page.html"), Some("JavaScript"));
+        assert_eq!(lang("src/main.rs"), None);
+
+        assert!(config.is_excluded(&root.join("vendor/lib/a.rs")));
+        assert!(!config.is_excluded(&root.join("src/vendor/a.rs")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_language_is_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(&path, "[languages]\n\"*.x\" = \"klingon\"\n")?;
+        let err = ProjectConfig::load(&path, false).err().unwrap();
+        assert!(err.to_string().contains("Unknown language 'klingon'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_discover_stops_at_repository_root() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("checkout/.git"))?;
+        fs::create_dir_all(root.join("checkout/src"))?;
+        fs::write(root.join(CONFIG_FILE_NAME), "exclude = [\"*.rs\"]\n")?;
+        assert!(ProjectConfig::discover(&root.join("checkout/src"), false)?.is_none());
+
+        fs::write(root.join("checkout").join(CONFIG_FILE_NAME), "")?;
+        let config = ProjectConfig::discover(&root.join("checkout/src"), false)?.unwrap();
+        assert_eq!(config.path, root.join("checkout").join(CONFIG_FILE_NAME));
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammars_need_opt_in() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            "[grammars.klingon]\npath = \"missing.so\"\n\n[languages]\n\"*.tlh\" = \"klingon\"\n",
+        )?;
+        // Nothing is loaded, so the missing library goes unnoticed
+        let config = ProjectConfig::load(&path, false)?;
+        assert_eq!(config.skipped_grammars, vec!["klingon"]);
+        assert!(config.language_for(&dir.path().join("a.tlh")).is_none());
+
+        let err = ProjectConfig::load(&path, true).err().unwrap();
+        assert!(err.to_string().contains("Failed to load grammar 'klingon'"));
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+use super::languages;
+use anyhow::{Context, Result, anyhow};
+use similar::{DiffOp, TextDiff};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use tree_sitter::{Node, Parser, Tree};
+
+/// How rewritten files are formatted after all rules have been applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Formatter {
+    /// Graft's own whitespace normalizer, which works for every language.
+    Builtin,
+    /// The usual external formatter for the file's language (see [`default_command`]).
+    Auto,
+    /// A command that reads the source on stdin and writes the formatted source to stdout.
+    /// `{file}` is replaced with the file's path.
+    Command(String),
+}
+
+impl FromStr for Formatter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("The formatter must not be empty".to_string()),
+            "builtin" => Ok(Self::Builtin),
+            "auto" => Ok(Self::Auto),
+            command => Ok(Self::Command(command.to_string())),
+        }
+    }
+}
+
+/// Which part of a rewritten file the formatter may change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FormatScope {
+    /// Only lines changed by the rules; formatting elsewhere in the file is left as it was.
+    #[default]
+    Changed,
+    /// The whole file.
+    File,
+}
+
+impl FromStr for FormatScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "changed" => Ok(Self::Changed),
+            "file" => Ok(Self::File),
+            _ => Err(format!(
+                "Invalid format scope '{}'. Expected one of: changed, file",
+                s
+            )),
+        }
+    }
+}
+
+/// The external formatter usually used for a language, if graft knows one.
+///
+/// Like `cargo fmt`, rustfmt is given the edition of the crate `path` belongs to, since it
+/// cannot tell from source read on stdin.
+pub fn default_command(lang_name: &str, path: &Path) -> Option<String> {
+    let spec = languages::find_spec(lang_name)?;
+    match spec.name {
+        "Rust" => Some(match rust_edition(path) {
+            Some(edition) => format!("rustfmt --edition {}", edition),
+            None => "rustfmt".to_string(),
+        }),
+        "Go" => Some("gofmt".to_string()),
+        "Python" => Some("black --quiet -".to_string()),
+        "JavaScript" | "TypeScript" | "TSX" | "JSON" | "CSS" | "HTML" | "Markdown" | "YAML" => {
+            Some("prettier --stdin-filepath {file}".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// The edition of the crate `path` belongs to, from the nearest `Cargo.toml` with a
+/// `[package]`, following `edition.workspace = true` up to the workspace's manifest.
+fn rust_edition(path: &Path) -> Option<String> {
+    let path = std::path::absolute(path).ok()?;
+    let mut inherited = false;
+    for dir in path.ancestors().skip(1) {
+        let Some(manifest) = std::fs::read_to_string(dir.join("Cargo.toml"))
+            .ok()
+            .and_then(|m| m.parse::<toml::Table>().ok())
+        else {
+            continue;
+        };
+        if inherited {
+            let workspace = manifest.get("workspace").and_then(|w| w.get("package"));
+            if let Some(edition) = workspace.and_then(|p| p.get("edition")?.as_str()) {
+                return Some(edition.to_string());
+            }
+            continue;
+        }
+        let Some(package) = manifest.get("package") else {
+            continue;
+        };
+        match package.get("edition") {
+            Some(toml::Value::String(edition)) => return Some(edition.clone()),
+            Some(_) => inherited = true,
+            // Cargo's default when a package names no edition
+            None => return Some("2015".to_string()),
+        }
+    }
+    None
+}
+
+/// Formats `transformed`, the result of rewriting `original`.
+///
+/// With [`FormatScope::Changed`], the formatter still sees the whole file, but only its
+/// changes to lines that differ from `original` are kept.
+pub fn format(
+    original: &str,
+    transformed: &str,
+    lang_name: &str,
+    path: &Path,
+    formatter: &Formatter,
+    scope: FormatScope,
+) -> Result<String> {
+    let formatted = match formatter {
+        Formatter::Builtin => normalize(transformed, lang_name)?,
+        Formatter::Auto => {
+            let command = default_command(lang_name, path).ok_or_else(|| {
+                anyhow!("No default formatter is known for language '{}'", lang_name)
+            })?;
+            run_command(&command, transformed, path)?
+        }
+        Formatter::Command(command) => run_command(command, transformed, path)?,
+    };
+
+    Ok(match scope {
+        FormatScope::File => formatted,
+        FormatScope::Changed => keep_changed_lines(original, transformed, &formatted),
+    })
+}
+
+fn run_command(command: &str, source: &str, path: &Path) -> Result<String> {
+    let path = path.to_string_lossy();
+    let mut args = command
+        .split_whitespace()
+        .map(|arg| arg.replace("{file}", &path));
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow!("Empty formatter command"))?;
+
+    let mut child = Command::new(&program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run formatter '{}'", program))?;
+    // Write from another thread so a formatter that streams its output cannot deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = source.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to run formatter '{}'", program))?;
+    let written = writer
+        .join()
+        .map_err(|_| anyhow!("Failed to write to formatter '{}'", program))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Formatter '{}' failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    written.with_context(|| format!("Failed to write to formatter '{}'", program))?;
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("Formatter '{}' did not produce valid UTF-8", program))
+}
+
+/// Applies the parts of `formatted` that touch lines of `transformed` which differ from
+/// `original`, and drops the rest.
+fn keep_changed_lines(original: &str, transformed: &str, formatted: &str) -> String {
+    let mut changed = vec![false; transformed.split_inclusive('\n').count() + 1];
+    for op in TextDiff::from_lines(original, transformed).ops() {
+        if !matches!(op, DiffOp::Equal { .. }) {
+            let range = op.new_range();
+            // A deletion changes the line it leaves behind
+            changed[range.start..range.end.max(range.start + 1)].fill(true);
+        }
+    }
+
+    let transformed_lines: Vec<&str> = transformed.split_inclusive('\n').collect();
+    let formatted_lines: Vec<&str> = formatted.split_inclusive('\n').collect();
+    let mut out = String::new();
+    for op in TextDiff::from_lines(transformed, formatted).ops() {
+        let (old, new) = (op.old_range(), op.new_range());
+        if matches!(op, DiffOp::Equal { .. }) {
+            out.extend(transformed_lines[old].iter().copied());
+        } else if old.len() == new.len() {
+            // Reformatted lines pair up one to one, so decide line by line
+            for (o, n) in old.zip(new) {
+                out.push_str(if changed[o] {
+                    formatted_lines[n]
+                } else {
+                    transformed_lines[o]
+                });
+            }
+        } else if changed[old.start..old.end.max(old.start + 1)].contains(&true) {
+            out.extend(formatted_lines[new].iter().copied());
+        } else {
+            out.extend(transformed_lines[old].iter().copied());
+        }
+    }
+    out
+}
+
+/// Languages in which the spaces between tokens are syntax, as in Bash's `[ -f x ]` or Make's
+/// `$(subst a,b,c)`. Only their trailing whitespace is normalized.
+const SPACE_SENSITIVE: &[&str] = &["Bash", "Make", "Dockerfile"];
+
+/// Normalizes whitespace between the tokens of each line, using the syntax tree to tell
+/// tokens apart: runs of spaces collapse to one, spaces after opening and before closing
+/// brackets, commas and semicolons are removed, a space follows every comma, and trailing
+/// whitespace is stripped. Indentation, line breaks, strings and comments are left alone,
+/// and so is everything but trailing whitespace in the languages of [`SPACE_SENSITIVE`].
+pub fn normalize(source: &str, lang_name: &str) -> Result<String> {
+    let language = languages::get_language(lang_name)?;
+    let space_sensitive =
+        languages::find_spec(lang_name).is_some_and(|spec| SPACE_SENSITIVE.contains(&spec.name));
+    let mut parser = Parser::new();
+    parser
+        .set_language(&language)
+        .context("Error loading language into parser")?;
+    let tree = parser
+        .parse(source, None)
+        .ok_or_else(|| anyhow!("Failed to parse source"))?;
+
+    let mut tokens = Vec::new();
+    collect_tokens(tree.root_node(), &mut tokens);
+
+    // Only the gaps between tokens are rewritten, so the whitespace inside strings, comments
+    // and heredocs is kept, trailing or not
+    let mut normalized = String::with_capacity(source.len());
+    let mut last = 0;
+    let ends = std::iter::once(0).chain(tokens.iter().map(|t| t.end_byte()));
+    let starts = tokens.iter().map(|t| t.start_byte()).chain([source.len()]);
+    for (i, (start, end)) in ends.zip(starts).enumerate() {
+        if start > end {
+            continue;
+        }
+        let gap = &source[start..end];
+        let wanted = if gap.contains('\n') || end == source.len() {
+            strip_trailing_whitespace(gap, end == source.len())
+        } else if space_sensitive || i == 0 || !gap.chars().all(|c| c == ' ' || c == '\t') {
+            continue;
+        } else {
+            let prev_text = &source[tokens[i - 1].byte_range()];
+            let next_text = &source[tokens[i].byte_range()];
+            if matches!(prev_text, "(" | "[") || matches!(next_text, ")" | "]" | "," | ";") {
+                String::new()
+            } else if prev_text == "," || !gap.is_empty() {
+                " ".to_string()
+            } else {
+                continue;
+            }
+        };
+        if wanted != gap {
+            normalized.push_str(&source[last..start]);
+            normalized.push_str(&wanted);
+            last = end;
+        }
+    }
+    normalized.push_str(&source[last..]);
+    if has_more_errors(&mut parser, &normalized, &tree) {
+        return Err(anyhow!(
+            "Whitespace normalization would introduce a syntax error"
+        ));
+    }
+    Ok(normalized)
+}
+
+/// Collects the leaves of the tree, treating strings, comments and heredocs as single tokens.
+fn collect_tokens<'t>(node: Node<'t>, tokens: &mut Vec<Node<'t>>) {
+    if node.child_count() == 0 || languages::is_literal_text(node.kind()) {
+        if node.end_byte() > node.start_byte() {
+            tokens.push(node);
+        }
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect_tokens(child, tokens);
+    }
+}
+
+/// Removes the spaces and tabs before each line break of `gap`, and at its end if it ends the
+/// file.
+fn strip_trailing_whitespace(gap: &str, ends_file: bool) -> String {
+    let stripped: String = gap
+        .split_inclusive('\n')
+        .map(|line| {
+            let content = line.trim_end_matches(['\n', '\r']);
+            if content.len() == line.len() {
+                // The indentation of the next token
+                return line.to_string();
+            }
+            format!(
+                "{}{}",
+                content.trim_end_matches([' ', '\t']),
+                &line[content.len()..]
+            )
+        })
+        .collect();
+    if ends_file {
+        stripped.trim_end_matches([' ', '\t']).to_string()
+    } else {
+        stripped
+    }
+}
+
+fn has_more_errors(parser: &mut Parser, source: &str, before: &Tree) -> bool {
+    let count = |tree: &Tree| {
+        let mut errors = 0;
+        let mut cursor = tree.walk();
+        let mut stack = vec![tree.root_node()];
+        while let Some(node) = stack.pop() {
+            if node.is_error() || node.is_missing() {
+                errors += 1;
+            }
+            if node.has_error() {
+                stack.extend(node.children(&mut cursor));
+            }
+        }
+        errors
+    };
+    parser
+        .parse(source, None)
+        .is_none_or(|after| count(&after) > count(before))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_whitespace() -> Result<()> {
+        let source =
+            "fn main() {\n    f( a ,b,  \"x  y\" );   // keep  this\n    let v = [ 1,2 ];  \n}\n";
+        assert_eq!(
+            normalize(source, "rust")?,
+            "fn main() {\n    f(a, b, \"x  y\"); // keep  this\n    let v = [1, 2];\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-bash")]
+    fn test_normalize_leaves_shell_spacing_alone() -> Result<()> {
+        let source = "if [ -f x ];  then  \n  a=( 1 2 )\nfi\ncase $x in\n  a | b ) echo ;;\nesac\n(( i = ( 1 + 2 ) ))\t\n";
+        assert_eq!(
+            normalize(source, "bash")?,
+            source.replace("  \n", "\n").replace("\t\n", "\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_keeps_trailing_whitespace_in_literals() -> Result<()> {
+        let source = "fn main() {  \n    let s = \"a  \n  b\";\t\n    /* c  \n    */\n}  ";
+        assert_eq!(
+            normalize(source, "rust")?,
+            "fn main() {\n    let s = \"a  \n  b\";\n    /* c  \n    */\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang-bash", feature = "lang-markdown"))]
+    fn test_normalize_keeps_heredocs_and_hard_breaks() -> Result<()> {
+        let source = "if x; then  \n  cat <<EOF\nhello  \nEOF\nfi\n";
+        assert_eq!(
+            normalize(source, "bash")?,
+            "if x; then\n  cat <<EOF\nhello  \nEOF\nfi\n"
+        );
+
+        let source = "First line  \nsecond line\n";
+        assert_eq!(normalize(source, "markdown")?, source);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-rust")]
+    fn test_rustfmt_uses_crate_edition() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("member/src"))?;
+        std::fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n\n[workspace.package]\nedition = \"2021\"\n",
+        )?;
+        std::fs::write(
+            root.join("member/Cargo.toml"),
+            "[package]\nname = \"member\"\nedition.workspace = true\n",
+        )?;
+        let command = |path: &str| default_command("rust", &root.join(path));
+
+        assert_eq!(
+            command("member/src/lib.rs").as_deref(),
+            Some("rustfmt --edition 2021")
+        );
+        std::fs::write(
+            root.join("member/Cargo.toml"),
+            "[package]\nname = \"member\"\n",
+        )?;
+        assert_eq!(
+            command("member/src/lib.rs").as_deref(),
+            Some("rustfmt --edition 2015")
+        );
+        // Outside of any package rustfmt falls back to its own configuration
+        assert_eq!(command("script.rs").as_deref(), Some("rustfmt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_changed_lines_only() {
+        let original = "a  =  1\nb  =  2\n";
+        let transformed = "a  =  1\nb  =  3\n";
+        let formatted = "a = 1\nb = 3\n";
+        assert_eq!(
+            keep_changed_lines(original, transformed, formatted),
+            "a  =  1\nb = 3\n"
+        );
+    }
+
+    #[test]
+    fn test_command_formatter() -> Result<()> {
+        let path = Path::new("a.txt");
+        let formatted = format(
+            "x\n",
+            "y\n",
+            "rust",
+            path,
+            &Formatter::Command("tr y z".to_string()),
+            FormatScope::File,
+        )?;
+        assert_eq!(formatted, "z\n");
+
+        let result = format(
+            "x\n",
+            "y\n",
+            "rust",
+            path,
+            &Formatter::Command("false".to_string()),
+            FormatScope::File,
+        );
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Formatter 'false' failed")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use super::languages::{self, LanguageSpec};
+use anyhow::{Context, Result, anyhow};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Node, Parser, Query, QueryCursor, QueryMatch};
+
+const CONTENT: &str = "injection.content";
+const LANGUAGE: &str = "injection.language";
+const COMBINED: &str = "injection.combined";
+const INCLUDE_CHILDREN: &str = "injection.include-children";
+/// Graft's own property: leaves out the first line of the content, such as the `|` header
+/// of a YAML block scalar.
+const SKIP_FIRST_LINE: &str = "injection.skip-first-line";
+
+/// Code in another language embedded in a file, such as JavaScript in an HTML `<script>`.
+pub struct Injection {
+    pub language: &'static LanguageSpec,
+    /// Byte ranges of the host source that make up the embedded code, in order. Combined
+    /// injections, like the string fragments around Nix interpolations, have several.
+    pub ranges: Vec<Range<usize>>,
+}
+
+/// Finds the code embedded in `source` with the host language's injection queries, in source
+/// order. Embedded code in languages graft does not bundle is ignored.
+///
+/// Supports the usual `@injection.content` and `@injection.language` captures and the
+/// `injection.language`, `injection.combined` and `injection.include-children` properties.
+pub fn find(source: &str, host: &LanguageSpec) -> Result<Vec<Injection>> {
+    if host.injections.is_empty() {
+        return Ok(Vec::new());
+    }
+    let language = host.language()?;
+    let query = Query::new(&language, &host.injections.join("\n"))
+        .with_context(|| format!("Invalid injections query for language '{}'", host.name))?;
+    let content = query.capture_index_for_name(CONTENT).ok_or_else(|| {
+        anyhow!(
+            "The injections query of '{}' has no @{}",
+            host.name,
+            CONTENT
+        )
+    })?;
+    let language_capture = query.capture_index_for_name(LANGUAGE);
+
+    let mut parser = Parser::new();
+    parser
+        .set_language(&language)
+        .context("Error loading language into parser")?;
+    let tree = parser
+        .parse(source, None)
+        .ok_or_else(|| anyhow!("Failed to parse source"))?;
+
+    let mut injections = Vec::new();
+    // Combined injections collect the ranges of every match of their pattern
+    let mut combined: BTreeMap<(usize, &'static str), Injection> = BTreeMap::new();
+    let mut cursor = QueryCursor::new();
+    let mut matches = cursor.matches(&query, tree.root_node(), source.as_bytes());
+    while let Some(m) = matches.next() {
+        let property = |key: &str| {
+            query
+                .property_settings(m.pattern_index)
+                .iter()
+                .find(|p| p.key.as_ref() == key)
+        };
+        let name = match property(LANGUAGE).and_then(|p| p.value.as_deref()) {
+            Some(name) => name,
+            None => match language_capture.and_then(|i| m.nodes_for_capture_index(i).next()) {
+                Some(node) => &source[node.byte_range()],
+                None => continue,
+            },
+        };
+        let Some(spec) = resolve_language(name) else {
+            continue;
+        };
+
+        let include_children = property(INCLUDE_CHILDREN).is_some();
+        let skip_first_line = property(SKIP_FIRST_LINE).is_some();
+        let ranges = content_ranges(m, content, source, include_children, skip_first_line);
+        if property(COMBINED).is_some() {
+            combined
+                .entry((m.pattern_index, spec.name))
+                .or_insert_with(|| Injection {
+                    language: spec,
+                    ranges: Vec::new(),
+                })
+                .ranges
+                .extend(ranges);
+        } else if !ranges.is_empty() {
+            injections.push(Injection {
+                language: spec,
+                ranges,
+            });
+        }
+    }
+
+    injections.extend(combined.into_values().filter(|i| !i.ranges.is_empty()));
+    for injection in &mut injections {
+        injection.ranges.sort_by_key(|r| r.start);
+    }
+    injections.sort_by_key(|i| i.ranges[0].start);
+    Ok(injections)
+}
+
+/// Resolves the name an injection gives its language, such as the info string `sh` of a
+/// Markdown code block or a `/* bash */` comment in Nix.
+fn resolve_language(name: &str) -> Option<&'static LanguageSpec> {
+    let name = name
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '{')
+        .next()?
+        .to_lowercase();
+    languages::find_spec(&name).or_else(|| {
+        languages::all().into_iter().find(|lang| {
+            lang.interpreters.contains(&name.as_str()) || lang.modelines.contains(&name.as_str())
+        })
+    })
+}
+
+fn content_ranges(
+    m: &QueryMatch,
+    content: u32,
+    source: &str,
+    include_children: bool,
+    skip_first_line: bool,
+) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    for node in m.nodes_for_capture_index(content) {
+        let mut start = node.start_byte();
+        if skip_first_line {
+            start = source[start..node.end_byte()]
+                .find('\n')
+                .map_or(node.end_byte(), |i| start + i + 1);
+        }
+        if include_children {
+            ranges.push(start..node.end_byte());
+        } else {
+            ranges.extend(without_children(node, start));
+        }
+    }
+    ranges.retain(|r| !r.is_empty());
+    ranges
+}
+
+/// The parts of `node` from `start` on that are not covered by one of its children.
+fn without_children(node: Node, start: usize) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut from = start;
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.start_byte() > from {
+            ranges.push(from..child.start_byte());
+        }
+        from = from.max(child.end_byte());
+    }
+    if node.end_byte() > from {
+        ranges.push(from..node.end_byte());
+    }
+    ranges
+}
+
+/// Maps `ranges` of the source before an edit of `start..old_end` into `start..new_end` to the
+/// source after it. The edit must lie within one of the ranges or outside all of them.
+pub(crate) fn shift_ranges(
+    ranges: &mut [Range<usize>],
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+) {
+    let shift = |byte: usize| byte + new_end - old_end;
+    for range in ranges {
+        if range.start <= start && old_end <= range.end {
+            range.end = shift(range.end);
+        } else if range.start >= old_end {
+            *range = shift(range.start)..shift(range.end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(
+        feature = "lang-markdown",
+        feature = "lang-yaml",
+        feature = "lang-nix",
+        feature = "lang-html",
+        feature = "lang-bash"
+    ))]
+    fn embedded(source: &str, lang: &str) -> Result<Vec<(&'static str, Vec<String>)>> {
+        let host = languages::find_spec(lang).unwrap();
+        Ok(find(source, host)?
+            .into_iter()
+            .map(|i| {
+                let texts = i.ranges.iter().map(|r| source[r.clone()].to_string());
+                (i.language.name, texts.collect())
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_injection_queries_compile() -> Result<()> {
+        for lang in languages::LANGUAGES {
+            find("", lang).with_context(|| lang.name)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-markdown",
+        feature = "lang-yaml",
+        feature = "lang-nix",
+        feature = "lang-html",
+        feature = "lang-bash"
+    ))]
+    fn test_find_injections() -> Result<()> {
+        let markdown = "# Build\n\n```sh\nmake all\n```\n\n```klingon\nqapla'\n```\n";
+        assert_eq!(
+            embedded(markdown, "markdown")?,
+            vec![("Bash", vec!["make all".to_string()])]
+        );
+
+        let yaml = "steps:\n  - run: |\n      make\n      make test\n  - run: echo done\n";
+        assert_eq!(
+            embedded(yaml, "yaml")?,
+            vec![
+                ("Bash", vec!["      make\n      make test".to_string()]),
+                ("Bash", vec!["echo done".to_string()])
+            ]
+        );
+
+        // Interpolations are left out of the combined script
+        let nix = "{ buildPhase = ''\n  make ${target}\n''; }\n";
+        assert_eq!(
+            embedded(nix, "nix")?,
+            vec![("Bash", vec!["\n  make ".to_string(), "\n".to_string()])]
+        );
+
+        let html = "<script>let a = 1;</script><style>p { }</style>";
+        assert_eq!(
+            embedded(html, "html")?,
+            vec![
+                ("JavaScript", vec!["let a = 1;".to_string()]),
+                ("CSS", vec!["p { }".to_string()])
+            ]
+        );
+        Ok(())
+    }
+}
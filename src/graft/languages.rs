@@ -1,218 +1,779 @@
+use super::loader;
 use anyhow::{Result, anyhow};
-use std::ffi::c_void;
-use std::mem;
-use tree_sitter::Language;
+use glob::Pattern;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex, RwLock};
+use tree_sitter::{Language, Parser};
+use tree_sitter_language::LanguageFn;
 
 pub struct LanguageSpec {
     pub name: &'static str,
     pub extensions: &'static [&'static str],
-    pub get_language: fn() -> Language,
+    /// Exact file names, such as `Makefile`.
+    pub filenames: &'static [&'static str],
+    /// Glob patterns matched against the file name, such as `Dockerfile.*`.
+    pub globs: &'static [&'static str],
+    /// Interpreters named in a `#!` line, such as `python3`. Version suffixes are ignored.
+    pub interpreters: &'static [&'static str],
+    /// Names used in Vim (`ft=`) and Emacs (`mode:`) modelines, besides the extensions.
+    pub modelines: &'static [&'static str],
+    /// Function returning the tree-sitter grammar. Use [`LanguageSpec::language`], which
+    /// validates it first.
+    pub grammar: LanguageFn,
+    /// `locals.scm`-style queries (`@local.scope`, `@local.definition`, `@local.reference`,
+    /// `@local.declaration`), concatenated in order. Empty if the language has none.
+    pub locals: &'static [&'static str],
+    /// `injections.scm`-style queries finding code in other languages embedded in this one,
+    /// concatenated in order. Empty if the language has none.
+    pub injections: &'static [&'static str],
 }
 
-// Category A: Compatible
-fn lang_rust() -> Language {
-    tree_sitter_rust::LANGUAGE.into()
-}
-fn lang_js() -> Language {
-    tree_sitter_javascript::LANGUAGE.into()
-}
-fn lang_ts() -> Language {
-    tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into()
-}
-fn lang_tsx() -> Language {
-    tree_sitter_typescript::LANGUAGE_TSX.into()
-}
-fn lang_go() -> Language {
-    tree_sitter_go::LANGUAGE.into()
-}
-fn lang_py() -> Language {
-    tree_sitter_python::LANGUAGE.into()
-}
-fn lang_c() -> Language {
-    tree_sitter_c::LANGUAGE.into()
-}
-fn lang_cpp() -> Language {
-    tree_sitter_cpp::LANGUAGE.into()
-}
-fn lang_java() -> Language {
-    tree_sitter_java::LANGUAGE.into()
-}
-fn lang_json() -> Language {
-    tree_sitter_json::LANGUAGE.into()
-}
-fn lang_html() -> Language {
-    tree_sitter_html::LANGUAGE.into()
-}
-fn lang_css() -> Language {
-    tree_sitter_css::LANGUAGE.into()
-}
-fn lang_bash() -> Language {
-    tree_sitter_bash::LANGUAGE.into()
-}
-fn lang_ocaml() -> Language {
-    tree_sitter_ocaml::LANGUAGE_OCAML.into()
-}
-fn lang_ocaml_interface() -> Language {
-    tree_sitter_ocaml::LANGUAGE_OCAML_INTERFACE.into()
+/// A bundled language left out of this build: enough to recognize its files and to name the
+/// cargo feature that enables it.
+struct DisabledLanguage {
+    feature: &'static str,
+    patterns: Patterns,
 }
 
-// Category B: Language wrapper mismatch
-// We expect `old` to be a struct wrapping a pointer.
-fn transmute_lang<L>(old: L) -> Language {
-    unsafe { mem::transmute_copy(&old) }
+/// The names and file patterns [`detect`] recognizes a language by.
+#[derive(Clone, Copy)]
+struct Patterns {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    filenames: &'static [&'static str],
+    globs: &'static [&'static str],
+    interpreters: &'static [&'static str],
+    modelines: &'static [&'static str],
 }
 
-fn lang_markdown() -> Language {
-    transmute_lang(tree_sitter_markdown::language())
-}
-fn lang_toml() -> Language {
-    transmute_lang(tree_sitter_toml::language())
+trait Detectable {
+    fn patterns(&self) -> Patterns;
 }
-fn lang_dockerfile() -> Language {
-    transmute_lang(tree_sitter_dockerfile::language())
+
+impl Detectable for LanguageSpec {
+    fn patterns(&self) -> Patterns {
+        Patterns {
+            name: self.name,
+            extensions: self.extensions,
+            filenames: self.filenames,
+            globs: self.globs,
+            interpreters: self.interpreters,
+            modelines: self.modelines,
+        }
+    }
 }
 
-// Category C: LanguageFn mismatch
-// LanguageFn is repr(transparent) wrapper around unsafe extern "C" fn() -> *const TSLanguage
-fn extract_from_fn<F>(f: F) -> Language {
-    // Transmute the struct F (LanguageFn) into a function pointer
-    let fn_ptr: unsafe extern "C" fn() -> *const c_void = unsafe { mem::transmute_copy(&f) };
-    let ptr = unsafe { fn_ptr() };
-    // Transmute the raw pointer to Language
-    unsafe { mem::transmute(ptr) }
+impl Detectable for DisabledLanguage {
+    fn patterns(&self) -> Patterns {
+        self.patterns
+    }
 }
 
-fn lang_lua() -> Language {
-    extract_from_fn(tree_sitter_lua::LANGUAGE)
+/// Why a grammar failed validation, by language name and grammar function, once it was checked.
+type HealthKey = (&'static str, usize);
+static HEALTH: LazyLock<Mutex<HashMap<HealthKey, Option<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl LanguageSpec {
+    /// The language's grammar, unless it failed validation (see [`LanguageSpec::health`]).
+    pub fn language(&self) -> Result<Language> {
+        self.health()
+            .map_err(|e| anyhow!("Language '{}' is disabled: {}", self.name, e))?;
+        Ok(self.grammar.into())
+    }
+
+    /// The extension files of this language are usually named with, such as `rs` for Rust.
+    /// Extensions that spell out the language's name, like `rust`, are skipped unless there is
+    /// no other.
+    pub fn file_extension(&self) -> Option<&'static str> {
+        let name = self.name.to_lowercase().replace(' ', "_");
+        self.extensions
+            .iter()
+            .find(|ext| **ext != name)
+            .or(self.extensions.first())
+            .copied()
+    }
+
+    /// Validates the grammar the first time it is called: its ABI version must be supported,
+    /// it must define node kinds, and it must parse an empty file. A grammar that fails is
+    /// never handed to the parser, so a broken grammar cannot cause undefined behavior later.
+    pub fn health(&self) -> std::result::Result<(), String> {
+        let mut health = HEALTH.lock().unwrap_or_else(|e| e.into_inner());
+        let problem = health
+            .entry((self.name, self.grammar.into_raw() as usize))
+            .or_insert_with(|| validate(self).err().map(|e| format!("{:#}", e)));
+        match problem {
+            Some(problem) => Err(problem.clone()),
+            None => Ok(()),
+        }
+    }
 }
-fn lang_nix() -> Language {
-    extract_from_fn(tree_sitter_nix::LANGUAGE)
+
+fn validate(spec: &LanguageSpec) -> Result<()> {
+    let language: Language = spec.grammar.into();
+    loader::check_abi(&language, spec.name)?;
+    if language.node_kind_count() == 0 {
+        return Err(anyhow!("Grammar '{}' defines no node kinds", spec.name));
+    }
+    let mut parser = Parser::new();
+    parser.set_language(&language)?;
+    parser
+        .parse("", None)
+        .ok_or_else(|| anyhow!("Grammar '{}' failed to parse an empty file", spec.name))?;
+    Ok(())
 }
-fn lang_yaml() -> Language {
-    extract_from_fn(tree_sitter_yaml::LANGUAGE)
+
+// These grammar crates are built against older tree-sitter versions, so their `Language` types
+// are not ours. The C functions they export are the same interface a grammar loaded at runtime
+// offers, and validation checks what they return like any other grammar.
+#[cfg(feature = "lang-dockerfile")]
+extern crate tree_sitter_dockerfile as _;
+#[cfg(feature = "lang-markdown")]
+extern crate tree_sitter_markdown as _;
+#[cfg(feature = "lang-toml")]
+extern crate tree_sitter_toml as _;
+
+unsafe extern "C" {
+    #[cfg(feature = "lang-dockerfile")]
+    fn tree_sitter_dockerfile() -> *const ();
+    #[cfg(feature = "lang-markdown")]
+    fn tree_sitter_markdown() -> *const ();
+    #[cfg(feature = "lang-toml")]
+    fn tree_sitter_toml() -> *const ();
 }
-fn lang_make() -> Language {
-    extract_from_fn(tree_sitter_make::LANGUAGE)
+
+// SAFETY: These are language functions generated by the tree-sitter CLI.
+#[cfg(feature = "lang-dockerfile")]
+const LANGUAGE_DOCKERFILE: LanguageFn = unsafe { LanguageFn::from_raw(tree_sitter_dockerfile) };
+#[cfg(feature = "lang-markdown")]
+const LANGUAGE_MARKDOWN: LanguageFn = unsafe { LanguageFn::from_raw(tree_sitter_markdown) };
+#[cfg(feature = "lang-toml")]
+const LANGUAGE_TOML: LanguageFn = unsafe { LanguageFn::from_raw(tree_sitter_toml) };
+
+/// Declares [`LANGUAGES`] and [`DISABLED`] from one table of `"lang-*" => LanguageSpec { .. }`
+/// entries, so that a language's feature, names and file patterns are only written once.
+macro_rules! bundled_languages {
+    ($($feature:literal => LanguageSpec {
+        name: $name:expr,
+        extensions: $extensions:expr,
+        filenames: $filenames:expr,
+        globs: $globs:expr,
+        interpreters: $interpreters:expr,
+        modelines: $modelines:expr,
+        grammar: $grammar:expr,
+        locals: $locals:expr,
+        injections: $injections:expr $(,)?
+    }),* $(,)?) => {
+        /// The languages bundled with this build of graft. Each one needs its `lang-*` cargo
+        /// feature.
+        pub const LANGUAGES: &[LanguageSpec] = &[$(
+            #[cfg(feature = $feature)]
+            LanguageSpec {
+                name: $name,
+                extensions: $extensions,
+                filenames: $filenames,
+                globs: $globs,
+                interpreters: $interpreters,
+                modelines: $modelines,
+                grammar: $grammar,
+                locals: $locals,
+                injections: $injections,
+            },
+        )*];
+
+        /// The bundled languages left out of this build, with the cargo feature that enables
+        /// them.
+        const DISABLED: &[DisabledLanguage] = &[$(
+            #[cfg(not(feature = $feature))]
+            DisabledLanguage {
+                feature: $feature,
+                patterns: Patterns {
+                    name: $name,
+                    extensions: $extensions,
+                    filenames: $filenames,
+                    globs: $globs,
+                    interpreters: $interpreters,
+                    modelines: $modelines,
+                },
+            },
+        )*];
+    };
 }
 
-pub const LANGUAGES: &[LanguageSpec] = &[
-    LanguageSpec {
+bundled_languages! {
+    "lang-rust" => LanguageSpec {
         name: "Rust",
         extensions: &["rust", "rs"],
-        get_language: lang_rust,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_rust::LANGUAGE,
+        locals: &[include_str!("../../queries/rust/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-javascript" => LanguageSpec {
         name: "JavaScript",
         extensions: &["javascript", "js", "jsx"],
-        get_language: lang_js,
+        filenames: &[],
+        globs: &[],
+        interpreters: &["node", "nodejs", "bun"],
+        modelines: &[],
+        grammar: tree_sitter_javascript::LANGUAGE,
+        locals: &[tree_sitter_javascript::LOCALS_QUERY],
+        injections: &[tree_sitter_javascript::INJECTIONS_QUERY],
     },
-    LanguageSpec {
+    "lang-typescript" => LanguageSpec {
         name: "TypeScript",
         extensions: &["typescript", "ts"],
-        get_language: lang_ts,
+        filenames: &[],
+        globs: &[],
+        interpreters: &["ts-node", "deno"],
+        modelines: &[],
+        grammar: tree_sitter_typescript::LANGUAGE_TYPESCRIPT,
+        locals: &[
+            tree_sitter_javascript::LOCALS_QUERY,
+            tree_sitter_typescript::LOCALS_QUERY,
+        ],
+        injections: &[tree_sitter_javascript::INJECTIONS_QUERY],
     },
-    LanguageSpec {
+    "lang-typescript" => LanguageSpec {
         name: "TSX",
         extensions: &["tsx"],
-        get_language: lang_tsx,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_typescript::LANGUAGE_TSX,
+        locals: &[
+            tree_sitter_javascript::LOCALS_QUERY,
+            tree_sitter_typescript::LOCALS_QUERY,
+        ],
+        injections: &[tree_sitter_javascript::INJECTIONS_QUERY],
     },
-    LanguageSpec {
+    "lang-go" => LanguageSpec {
         name: "Go",
         extensions: &["go"],
-        get_language: lang_go,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_go::LANGUAGE,
+        locals: &[include_str!("../../queries/go/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-python" => LanguageSpec {
         name: "Python",
         extensions: &["python", "py"],
-        get_language: lang_py,
+        filenames: &["SConstruct", "SConscript"],
+        globs: &[],
+        interpreters: &["python", "python2", "python3", "pypy", "pypy3"],
+        modelines: &[],
+        grammar: tree_sitter_python::LANGUAGE,
+        locals: &[include_str!("../../queries/python/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-c" => LanguageSpec {
         name: "C",
         extensions: &["c", "h"],
-        get_language: lang_c,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_c::LANGUAGE,
+        locals: &[include_str!("../../queries/c/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-cpp" => LanguageSpec {
         name: "C++",
         extensions: &["cpp", "c++", "cc", "cxx", "hpp", "hxx"],
-        get_language: lang_cpp,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_cpp::LANGUAGE,
+        locals: &[include_str!("../../queries/cpp/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-java" => LanguageSpec {
         name: "Java",
         extensions: &["java"],
-        get_language: lang_java,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_java::LANGUAGE,
+        locals: &[include_str!("../../queries/java/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-json" => LanguageSpec {
         name: "JSON",
         extensions: &["json"],
-        get_language: lang_json,
+        filenames: &[
+            "flake.lock",
+            ".babelrc",
+            ".eslintrc",
+            "composer.lock",
+            "Pipfile.lock",
+        ],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_json::LANGUAGE,
+        locals: &[],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-html" => LanguageSpec {
         name: "HTML",
         extensions: &["html"],
-        get_language: lang_html,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_html::LANGUAGE,
+        locals: &[],
+        injections: &[tree_sitter_html::INJECTIONS_QUERY],
     },
-    LanguageSpec {
+    "lang-css" => LanguageSpec {
         name: "CSS",
         extensions: &["css"],
-        get_language: lang_css,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_css::LANGUAGE,
+        locals: &[],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-bash" => LanguageSpec {
         name: "Bash",
         extensions: &["bash", "sh", "zsh"],
-        get_language: lang_bash,
+        filenames: &[
+            ".bashrc",
+            ".bash_profile",
+            ".bash_aliases",
+            ".profile",
+            ".zshrc",
+            ".zprofile",
+            "PKGBUILD",
+        ],
+        globs: &[],
+        interpreters: &["sh", "bash", "zsh", "dash", "ksh"],
+        modelines: &["shell-script"],
+        grammar: tree_sitter_bash::LANGUAGE,
+        locals: &[include_str!("../../queries/bash/locals.scm")],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-ocaml" => LanguageSpec {
         name: "OCaml",
         extensions: &["ocaml", "ml"],
-        get_language: lang_ocaml,
+        filenames: &[],
+        globs: &[],
+        interpreters: &["ocaml"],
+        modelines: &["tuareg"],
+        grammar: tree_sitter_ocaml::LANGUAGE_OCAML,
+        locals: &[tree_sitter_ocaml::LOCALS_QUERY],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-ocaml" => LanguageSpec {
         name: "OCaml Interface",
         extensions: &["ocaml_interface", "mli"],
-        get_language: lang_ocaml_interface,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_ocaml::LANGUAGE_OCAML_INTERFACE,
+        locals: &[tree_sitter_ocaml::LOCALS_QUERY],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-lua" => LanguageSpec {
         name: "Lua",
         extensions: &["lua"],
-        get_language: lang_lua,
+        filenames: &[],
+        globs: &[],
+        interpreters: &["lua", "luajit"],
+        modelines: &[],
+        grammar: tree_sitter_lua::LANGUAGE,
+        locals: &[tree_sitter_lua::LOCALS_QUERY],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-nix" => LanguageSpec {
         name: "Nix",
         extensions: &["nix"],
-        get_language: lang_nix,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_nix::LANGUAGE,
+        locals: &[],
+        injections: &[tree_sitter_nix::INJECTIONS_QUERY],
     },
-    LanguageSpec {
+    "lang-yaml" => LanguageSpec {
         name: "YAML",
         extensions: &["yaml", "yml"],
-        get_language: lang_yaml,
+        filenames: &[".clang-format", ".clang-tidy"],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: tree_sitter_yaml::LANGUAGE,
+        locals: &[],
+        injections: &[include_str!("../../queries/yaml/injections.scm")],
     },
-    LanguageSpec {
+    "lang-markdown" => LanguageSpec {
         name: "Markdown",
         extensions: &["markdown", "md"],
-        get_language: lang_markdown,
+        filenames: &[],
+        globs: &[],
+        interpreters: &[],
+        modelines: &["gfm"],
+        grammar: LANGUAGE_MARKDOWN,
+        locals: &[],
+        injections: &[include_str!("../../queries/markdown/injections.scm")],
     },
-    LanguageSpec {
+    "lang-toml" => LanguageSpec {
         name: "TOML",
         extensions: &["toml"],
-        get_language: lang_toml,
+        filenames: &["Cargo.lock", "Pipfile", "poetry.lock", "uv.lock"],
+        globs: &[],
+        interpreters: &[],
+        modelines: &[],
+        grammar: LANGUAGE_TOML,
+        locals: &[],
+        injections: &[],
     },
-    LanguageSpec {
+    "lang-dockerfile" => LanguageSpec {
         name: "Dockerfile",
         extensions: &["dockerfile", "docker"],
-        get_language: lang_dockerfile,
+        filenames: &["Dockerfile", "Containerfile"],
+        globs: &["Dockerfile.*", "*.Dockerfile", "Containerfile.*"],
+        interpreters: &[],
+        modelines: &[],
+        grammar: LANGUAGE_DOCKERFILE,
+        locals: &[],
+        injections: &[include_str!("../../queries/dockerfile/injections.scm")],
     },
-    LanguageSpec {
+    "lang-make" => LanguageSpec {
         name: "Make",
         extensions: &["make", "makefile", "mk"],
-        get_language: lang_make,
+        filenames: &["Makefile", "makefile", "GNUmakefile"],
+        globs: &["Makefile.*"],
+        interpreters: &["make"],
+        modelines: &["makefile-gmake", "makefile-bsdmake"],
+        grammar: tree_sitter_make::LANGUAGE,
+        locals: &[],
+        injections: &[],
     },
-];
+}
+
+/// Languages registered at runtime, in registration order.
+static REGISTERED: RwLock<Vec<&'static LanguageSpec>> = RwLock::new(Vec::new());
+
+/// Makes a language available next to [`LANGUAGES`] for the rest of the process.
+/// Registered languages take precedence over bundled ones with the same name or extensions,
+/// and later registrations over earlier ones.
+pub fn register(spec: LanguageSpec) -> &'static LanguageSpec {
+    let spec: &'static LanguageSpec = Box::leak(Box::new(spec));
+    REGISTERED
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(spec);
+    spec
+}
+
+/// Every known language: the registered ones, most recent first, then [`LANGUAGES`].
+pub fn all() -> Vec<&'static LanguageSpec> {
+    let registered = REGISTERED.read().unwrap_or_else(|e| e.into_inner());
+    registered
+        .iter()
+        .rev()
+        .copied()
+        .chain(LANGUAGES.iter())
+        .collect()
+}
+
+/// Finds a language by its name (case-insensitive) or one of its extensions.
+pub fn find_spec(lang_name: &str) -> Option<&'static LanguageSpec> {
+    all().into_iter().find(|lang| {
+        lang.extensions.contains(&lang_name) || lang.name.eq_ignore_ascii_case(lang_name)
+    })
+}
 
 pub fn get_language(lang_name: &str) -> Result<Language> {
-    for lang in LANGUAGES {
-        if lang.extensions.contains(&lang_name) || lang.name.eq_ignore_ascii_case(lang_name) {
-            return Ok((lang.get_language)());
+    find_spec(lang_name)
+        .ok_or_else(|| unsupported(lang_name))?
+        .language()
+}
+
+/// The cargo feature that would bundle `lang_name`, if it is the name or an extension of a
+/// language left out of this build.
+pub fn missing_feature(lang_name: &str) -> Option<&'static str> {
+    let name = lang_name.to_lowercase();
+    DISABLED
+        .iter()
+        .find(|lang| {
+            lang.patterns.name.eq_ignore_ascii_case(&name)
+                || lang.patterns.extensions.contains(&name.as_str())
+        })
+        .map(|lang| lang.feature)
+}
+
+/// The error for a language graft does not know, naming the missing cargo feature if the
+/// language was left out of this build.
+pub fn unsupported(lang_name: &str) -> anyhow::Error {
+    match missing_feature(lang_name) {
+        Some(feature) => anyhow!(
+            "Language '{}' is not included in this build of graft; rebuild it with the '{}' feature",
+            lang_name,
+            feature
+        ),
+        None => anyhow!("Unsupported language: {}", lang_name),
+    }
+}
+
+/// Whether nodes of this kind hold literal text, like strings, comments and heredocs, whose
+/// whitespace is part of the program and must be kept as it is.
+pub fn is_literal_text(kind: &str) -> bool {
+    kind.contains("string") || kind.contains("comment") || kind.contains("heredoc")
+}
+
+/// Number of lines at the start and end of a file searched for a Vim modeline, as Vim does.
+const MODELINE_LINES: usize = 5;
+
+static VIM_MODELINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|\s)(?:vi|vim|ex):\s*(?:set?\s+)?(?:.*[\s:])?(?:ft|filetype|syntax)=([\w+-]+)")
+        .unwrap()
+});
+static EMACS_MODELINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"-\*-\s*(?:(?:.*;)?\s*mode:\s*)?([\w+-]+)\s*(?:;.*)?-\*-").unwrap()
+});
+
+/// Detects the language of a file from its path and contents.
+///
+/// The checks run in this order, and the first one that matches wins:
+/// 1. An Emacs (`-*- mode: python -*-`) or Vim (`vim: set ft=python:`) modeline
+/// 2. The exact file name (`Makefile`)
+/// 3. A file name glob (`Dockerfile.*`)
+/// 4. The file extension
+/// 5. The interpreter of a `#!` line (`#!/usr/bin/env python3`)
+///
+/// The extension comes before the `#!` line because it is more specific: a `.ts` script run by
+/// `node` or `bun` is still TypeScript.
+pub fn detect(path: &Path, content: &str) -> Option<&'static LanguageSpec> {
+    detect_among(&all(), path, content)
+}
+
+/// The name of the language left out of this build that [`detect`] would have found for this
+/// file, so that the error can name the missing feature (see [`unsupported`]).
+pub fn detect_disabled(path: &Path, content: &str) -> Option<&'static str> {
+    let disabled: Vec<&DisabledLanguage> = DISABLED.iter().collect();
+    detect_among(&disabled, path, content).map(|lang| lang.patterns.name)
+}
+
+fn detect_among<'a, T: Detectable>(
+    languages: &[&'a T],
+    path: &Path,
+    content: &str,
+) -> Option<&'a T> {
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let find = |f: &dyn Fn(Patterns) -> bool| languages.iter().copied().find(|l| f(l.patterns()));
+    detect_modeline(content)
+        .and_then(|name| {
+            find(&|lang| {
+                lang.modelines.contains(&name.as_str())
+                    || lang.extensions.contains(&name.as_str())
+                    || lang.name.eq_ignore_ascii_case(&name)
+            })
+        })
+        .or_else(|| find(&|lang| lang.filenames.contains(&file_name)))
+        .or_else(|| {
+            find(&|lang| {
+                lang.globs
+                    .iter()
+                    .any(|g| Pattern::new(g).is_ok_and(|p| p.matches(file_name)))
+            })
+        })
+        .or_else(|| {
+            let ext = path.extension().and_then(|e| e.to_str())?;
+            find(&|lang| lang.extensions.contains(&ext))
+        })
+        .or_else(|| {
+            let (interpreter, unversioned) = detect_shebang(content)?;
+            find(&|lang| {
+                lang.interpreters.contains(&interpreter) || lang.interpreters.contains(&unversioned)
+            })
+        })
+}
+
+/// The language named by an Emacs or Vim modeline, lowercased.
+fn detect_modeline(content: &str) -> Option<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    // Emacs only looks at the first line, or the second after a `#!` line
+    let emacs = lines
+        .iter()
+        .take(if content.starts_with("#!") { 2 } else { 1 })
+        .find_map(|line| EMACS_MODELINE.captures(line));
+    let vim = || {
+        let tail = lines
+            .len()
+            .saturating_sub(MODELINE_LINES)
+            .max(MODELINE_LINES);
+        lines
+            .iter()
+            .take(MODELINE_LINES)
+            .chain(lines.iter().skip(tail))
+            .find_map(|line| VIM_MODELINE.captures(line))
+    };
+    Some(emacs.or_else(vim)?.get(1)?.as_str().to_lowercase())
+}
+
+/// The interpreter of a `#!` line, with and without its version suffix.
+fn detect_shebang(content: &str) -> Option<(&str, &str)> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        // `#!/usr/bin/env -S deno run` names the interpreter after the options
+        interpreter = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    let unversioned = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    Some((interpreter, unversioned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(path: &str, content: &str) -> Option<&'static str> {
+        detect(Path::new(path), content).map(|lang| lang.name)
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-make",
+        feature = "lang-dockerfile",
+        feature = "lang-json",
+        feature = "lang-rust"
+    ))]
+    fn test_detect_by_name_and_extension() {
+        assert_eq!(detected("src/Makefile", ""), Some("Make"));
+        assert_eq!(detected("Dockerfile.dev", ""), Some("Dockerfile"));
+        assert_eq!(detected("flake.lock", "{}"), Some("JSON"));
+        assert_eq!(detected("main.rs", ""), Some("Rust"));
+        // There is no bundled grammar for `just`
+        assert_eq!(detected("Justfile", ""), None);
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-python",
+        feature = "lang-bash",
+        feature = "lang-javascript",
+        feature = "lang-typescript"
+    ))]
+    fn test_detect_shebang() {
+        assert_eq!(
+            detected("deploy", "#!/usr/bin/env python3.12\n"),
+            Some("Python")
+        );
+        assert_eq!(detected("run", "#!/bin/bash -e\n"), Some("Bash"));
+        assert_eq!(
+            detected("tool", "#!/usr/bin/env -S node --experimental\n"),
+            Some("JavaScript")
+        );
+        // A known extension wins over the shebang, an unknown one does not
+        assert_eq!(
+            detected("server.ts", "#!/usr/bin/env node\n"),
+            Some("TypeScript")
+        );
+        assert_eq!(
+            detected("server.ts", "#!/usr/bin/env bun\n"),
+            Some("TypeScript")
+        );
+        assert_eq!(detected("build.txt", "#!/bin/sh\n"), Some("Bash"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "lang-make"))]
+    fn test_detect_disabled_language() {
+        assert!(detected("src/Makefile", "").is_none());
+        assert_eq!(detect_disabled(Path::new("src/Makefile"), ""), Some("Make"));
+        assert_eq!(detect_disabled(Path::new("build.mk"), ""), Some("Make"));
+        assert_eq!(missing_feature("makefile"), Some("lang-make"));
+        assert!(
+            unsupported("Make")
+                .to_string()
+                .contains("rebuild it with the 'lang-make' feature")
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang-python", feature = "lang-make", feature = "lang-yaml"))]
+    fn test_detect_modeline() {
+        assert_eq!(
+            detected("script", "# -*- mode: python; coding: utf-8 -*-\n"),
+            Some("Python")
+        );
+        assert_eq!(
+            detected("rules", "#!/bin/sh\n# -*- makefile-gmake -*-\n"),
+            Some("Make")
+        );
+        let vim = "x = 1\n\n\n\n\n\n\n# vim: set ts=4 ft=yaml:\n";
+        assert_eq!(detected("conf.txt", vim), Some("YAML"));
+        // The modeline wins over the file name
+        assert_eq!(detected("Makefile", "# vim: ft=python\n"), Some("Python"));
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lang-rust",
+        feature = "lang-javascript",
+        feature = "lang-ocaml",
+        feature = "lang-go"
+    ))]
+    fn test_file_extension() {
+        let extension = |lang| find_spec(lang).and_then(|spec| spec.file_extension());
+        assert_eq!(extension("rust"), Some("rs"));
+        assert_eq!(extension("JavaScript"), Some("js"));
+        assert_eq!(extension("ocaml_interface"), Some("mli"));
+        assert_eq!(extension("go"), Some("go"));
+    }
+
+    #[test]
+    fn test_bundled_grammars_are_healthy() {
+        for lang in LANGUAGES {
+            assert_eq!(lang.health(), Ok(()), "{}", lang.name);
         }
     }
-    Err(anyhow!("Unsupported language: {}", lang_name))
+
+    #[test]
+    fn test_incompatible_grammar_is_disabled() {
+        // Only the leading ABI version field of the language struct is read before rejecting it
+        unsafe extern "C" fn future_grammar() -> *const () {
+            static LANGUAGE: [u32; 8] = [99, 0, 0, 0, 0, 0, 0, 0];
+            LANGUAGE.as_ptr().cast()
+        }
+        let spec = register(LanguageSpec {
+            name: "future-grammar",
+            extensions: &["future-grammar"],
+            filenames: &[],
+            globs: &[],
+            interpreters: &[],
+            modelines: &[],
+            grammar: unsafe { LanguageFn::from_raw(future_grammar) },
+            locals: &[],
+            injections: &[],
+        });
+
+        assert!(spec.health().unwrap_err().contains("ABI version 99"));
+        let err = get_language("future-grammar").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Language 'future-grammar' is disabled")
+        );
+    }
 }
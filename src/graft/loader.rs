@@ -0,0 +1,334 @@
+use anyhow::{Context, Result, anyhow};
+use libloading::{Library, Symbol};
+use regex::Regex;
+use std::env;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{LazyLock, Mutex};
+use tree_sitter::{LANGUAGE_VERSION, Language, MIN_COMPATIBLE_LANGUAGE_VERSION};
+use tree_sitter_language::LanguageFn;
+
+/// Libraries of loaded grammars. They are never unloaded, since languages borrowed from them
+/// can live for the rest of the process.
+static LIBRARIES: Mutex<Vec<Library>> = Mutex::new(Vec::new());
+
+/// The symbol a grammar named `name` exports by convention, e.g. `tree_sitter_kotlin`.
+pub fn default_symbol(name: &str) -> String {
+    format!("tree_sitter_{}", name.replace('-', "_"))
+}
+
+/// Loads the language function `symbol` from the compiled grammar at `path`.
+///
+/// Fails if the grammar was generated for an ABI version this build of tree-sitter cannot use.
+pub fn load_shared(path: &Path, symbol: &str) -> Result<LanguageFn> {
+    // SAFETY: Loading a library runs its initializers, and a generated `parser.c` defines none.
+    // Only the language function is looked up: the external scanner functions of a grammar that
+    // has one are referenced by address from its static `TSLanguage`, so a library built
+    // without `scanner.c` has undefined symbols and fails to load here, even with lazy binding,
+    // instead of calling a missing function while parsing.
+    let library = unsafe { Library::new(path) }
+        .with_context(|| format!("Failed to load grammar library {:?}", path))?;
+    // SAFETY: Grammar libraries export their language as `const TSLanguage *symbol(void)`.
+    let language_fn = unsafe {
+        let f: Symbol<unsafe extern "C" fn() -> *const ()> = library
+            .get(symbol.as_bytes())
+            .with_context(|| format!("Grammar library {:?} does not export '{}'", path, symbol))?;
+        LanguageFn::from_raw(*f)
+    };
+    check_abi(&language_fn.into(), symbol)?;
+
+    LIBRARIES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(library);
+    Ok(language_fn)
+}
+
+/// Overrides the directory compiled grammars are cached in.
+pub const CACHE_DIR_ENV: &str = "GRAFT_CACHE_DIR";
+
+static LANGUAGE_VERSION_DEFINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^#define LANGUAGE_VERSION (\d+)").unwrap());
+
+/// Compiles the grammar checkout at `source` (its `src/parser.c`, and `src/scanner.c` if
+/// present) into a shared library with the system C compiler, or `$CC`, and returns the path.
+///
+/// Libraries are cached per user, keyed by a hash of the sources, the compiler and the target,
+/// and by the grammar's ABI version, so a grammar is only compiled again after one of them
+/// changed.
+pub fn build_from_source(name: &str, source: &Path) -> Result<PathBuf> {
+    build_into(name, source, &cache_dir()?)
+}
+
+fn build_into(name: &str, source: &Path, cache: &Path) -> Result<PathBuf> {
+    let src = source.join("src");
+    let parser = src.join("parser.c");
+    let parser_source = fs::read_to_string(&parser).with_context(|| {
+        format!(
+            "Failed to read {:?}; is this a tree-sitter grammar?",
+            parser
+        )
+    })?;
+    // Check before compiling, rather than after loading a library we cannot use
+    let version: usize = LANGUAGE_VERSION_DEFINE
+        .captures(&parser_source)
+        .and_then(|c| c[1].parse().ok())
+        .ok_or_else(|| anyhow!("{:?} does not define LANGUAGE_VERSION", parser))?;
+    if !(MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+        return Err(anyhow!(
+            "Grammar '{}' was generated for ABI version {}, but graft supports versions {} to {}; \
+             regenerate it with a matching tree-sitter CLI",
+            name,
+            version,
+            MIN_COMPATIBLE_LANGUAGE_VERSION,
+            LANGUAGE_VERSION
+        ));
+    }
+
+    let scanner = src.join("scanner.c");
+    let scanner = scanner.is_file().then_some(scanner);
+    // The generated headers in `src/tree_sitter` are compiled in as well
+    let mut inputs: Vec<PathBuf> = fs::read_dir(src.join("tree_sitter"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "h"))
+        .collect();
+    inputs.sort();
+    inputs.extend(scanner.clone());
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let mut key = CacheKey::new();
+    key.add(compiler.as_bytes());
+    key.add(env!("GRAFT_TARGET").as_bytes());
+    key.add(parser_source.as_bytes());
+    for input in &inputs {
+        key.add(&fs::read(input).with_context(|| format!("Failed to read {:?}", input))?);
+    }
+    let library = cache.join(format!(
+        "{}-{:016x}-abi{}.{}",
+        name,
+        key.finish(),
+        version,
+        env::consts::DLL_EXTENSION
+    ));
+    if library.is_file() {
+        return Ok(library);
+    }
+
+    let dir = library.parent().expect("cached library is in a directory");
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    // Compile next to the final path and rename, so a concurrent or interrupted build never
+    // leaves a partial library in the cache
+    let partial = tempfile::Builder::new()
+        .prefix(name)
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create a file in {:?}", dir))?;
+    let output = Command::new(&compiler)
+        .args(["-shared", "-fPIC", "-O2", "-I"])
+        .arg(&src)
+        .arg(&parser)
+        .args(&scanner)
+        .arg("-o")
+        .arg(partial.path())
+        .output()
+        .with_context(|| format!("Failed to run C compiler '{}'", compiler))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Compiling grammar '{}' failed with {}: {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    partial
+        .persist(&library)
+        .with_context(|| format!("Failed to write {:?}", library))?;
+    Ok(library)
+}
+
+/// Hashes the inputs of a grammar build. Unlike `DefaultHasher`, SipHash-2-4 with fixed keys
+/// gives the same hash in every graft release, so upgrading graft keeps the cache.
+struct CacheKey(#[allow(deprecated)] std::hash::SipHasher);
+
+impl CacheKey {
+    #[allow(deprecated)]
+    fn new() -> Self {
+        CacheKey(std::hash::SipHasher::new_with_keys(0, 0))
+    }
+
+    /// Adds `bytes`, prefixed with their length so consecutive inputs cannot run together.
+    fn add(&mut self, bytes: &[u8]) {
+        self.0.write(&(bytes.len() as u64).to_le_bytes());
+        self.0.write(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+/// `$GRAFT_CACHE_DIR`, or `graft/grammars` in the user's cache directory.
+fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(CACHE_DIR_ENV) {
+        return Ok(PathBuf::from(dir));
+    }
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot find a cache directory for compiled grammars; set {}",
+                CACHE_DIR_ENV
+            )
+        })?;
+    Ok(base.join("graft").join("grammars"))
+}
+
+/// Checks that `language` was generated for an ABI version supported by tree-sitter.
+pub fn check_abi(language: &Language, name: &str) -> Result<()> {
+    let version = language.abi_version();
+    if (MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Grammar '{}' uses ABI version {}, but graft supports versions {} to {}",
+            name,
+            version,
+            MIN_COMPATIBLE_LANGUAGE_VERSION,
+            LANGUAGE_VERSION
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_build_rejects_incompatible_or_missing_sources() -> Result<()> {
+        let dir = tempdir()?;
+        let err = build_from_source("missing", dir.path()).unwrap_err();
+        assert!(err.to_string().contains("is this a tree-sitter grammar?"));
+
+        fs::create_dir_all(dir.path().join("src"))?;
+        fs::write(
+            dir.path().join("src/parser.c"),
+            "#include \"tree_sitter/parser.h\"\n#define LANGUAGE_VERSION 99\n",
+        )?;
+        let err = build_from_source("future", dir.path()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Grammar 'future' was generated for ABI version 99")
+        );
+        Ok(())
+    }
+
+    /// Whether the C compiler grammars are built with is installed.
+    fn has_compiler() -> bool {
+        let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        Command::new(compiler)
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-json")]
+    fn test_registered_language() -> Result<()> {
+        use crate::graft::languages::{self, LanguageSpec};
+
+        assert!(languages::find_spec("registered-jsonc").is_none());
+        languages::register(LanguageSpec {
+            name: "registered-jsonc",
+            extensions: &["registered-jsonc"],
+            filenames: &[],
+            globs: &[],
+            interpreters: &[],
+            modelines: &[],
+            grammar: tree_sitter_json::LANGUAGE,
+            locals: &[],
+            injections: &[],
+        });
+
+        let detected = languages::detect(Path::new("a.registered-jsonc"), "{}");
+        assert_eq!(detected.map(|spec| spec.name), Some("registered-jsonc"));
+        let language = languages::get_language("Registered-JSONC")?;
+        assert!(language.id_for_node_kind("object", true) != 0);
+
+        assert!(load_shared(Path::new("/nonexistent.so"), "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_library_without_scanner() -> Result<()> {
+        if !has_compiler() {
+            eprintln!("skipping: no C compiler");
+            return Ok(());
+        }
+        let dir = tempdir()?;
+        let parser = dir.path().join("parser.c");
+        fs::write(
+            &parser,
+            "void *tree_sitter_x_external_scanner_create(void);\n\
+             static void *(*const scanner)(void) = tree_sitter_x_external_scanner_create;\n\
+             const void *tree_sitter_x(void) { return &scanner; }\n",
+        )?;
+        let library = dir.path().join(format!("x.{}", env::consts::DLL_EXTENSION));
+        let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        let status = Command::new(compiler)
+            .args(["-shared", "-fPIC"])
+            .arg(&parser)
+            .arg("-o")
+            .arg(&library)
+            .status()?;
+        if !status.success() {
+            // Linkers that reject undefined symbols, as on macOS, fail even earlier
+            return Ok(());
+        }
+
+        let err = load_shared(&library, "tree_sitter_x").err().unwrap();
+        assert!(err.to_string().contains("Failed to load grammar library"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_caches_and_loads_grammar() -> Result<()> {
+        if !has_compiler() {
+            eprintln!("skipping: no C compiler");
+            return Ok(());
+        }
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/json-grammar");
+        let cache = tempdir()?;
+        let library = build_into("json", &fixture, cache.path())?;
+        let built = fs::metadata(&library)?.modified()?;
+
+        // The second build is a cache hit and leaves the library alone
+        assert_eq!(build_into("json", &fixture, cache.path())?, library);
+        assert_eq!(fs::metadata(&library)?.modified()?, built);
+
+        // A change to the sources is a cache miss
+        let changed = tempdir()?;
+        fs::create_dir_all(changed.path().join("src/tree_sitter"))?;
+        for file in ["src/parser.c", "src/tree_sitter/parser.h"] {
+            fs::copy(fixture.join(file), changed.path().join(file))?;
+        }
+        let mut parser = fs::OpenOptions::new()
+            .append(true)
+            .open(changed.path().join("src/parser.c"))?;
+        std::io::Write::write_all(&mut parser, b"// changed\n")?;
+        assert_ne!(build_into("json", changed.path(), cache.path())?, library);
+
+        let language = load_shared(&library, "tree_sitter_json")?;
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(&language.into())?;
+        let tree = parser.parse("{\"a\": 1}", None).unwrap();
+        assert_eq!(
+            tree.root_node().to_sexp(),
+            "(document (object (pair key: (string (string_content)) value: (number))))"
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
+pub mod config;
+pub mod format;
+pub mod injections;
 pub mod languages;
+pub mod loader;
+pub mod rename;
 pub mod rules;
 
 mod transformer;
@@ -0,0 +1,829 @@
+use super::languages;
+use super::{ApplyOptions, Modification, SerializablePoint, Transformer};
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Language, Node, Parser, Query, QueryCursor, Tree};
+
+/// The kind of symbol being renamed. Each kind has its own definition and reference queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Type,
+    Field,
+}
+
+impl FromStr for SymbolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "function" => Ok(Self::Function),
+            "type" => Ok(Self::Type),
+            "field" => Ok(Self::Field),
+            _ => Err(format!(
+                "Invalid symbol kind '{}'. Expected one of: function, type, field",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Function => "function",
+            Self::Type => "type",
+            Self::Field => "field",
+        })
+    }
+}
+
+/// Queries for one kind of symbol. Every pattern captures the symbol's name as `@name`.
+struct KindQueries {
+    definitions: &'static str,
+    references: &'static str,
+    /// Names matched by `definitions` or `references` that belong to something else, such as
+    /// method calls that look like field accesses.
+    excludes: &'static str,
+    /// Uses that a rename would change in meaning, such as shorthand field initializers, or
+    /// that may not be the symbol at all, such as a call that may construct a tuple struct.
+    manual: &'static str,
+}
+
+/// How to find symbols in one language.
+struct RenameSpec {
+    /// Name of the language in [`languages::LANGUAGES`].
+    language: &'static str,
+    functions: KindQueries,
+    types: KindQueries,
+    fields: KindQueries,
+    /// Local variables and parameters, which can shadow a function or class.
+    locals: &'static str,
+    /// Node kinds that limit the visibility of the locals declared inside them.
+    scopes: &'static [&'static str],
+}
+
+const RUST: RenameSpec = RenameSpec {
+    language: "Rust",
+    functions: KindQueries {
+        definitions: "
+            (function_item name: (identifier) @name)
+            (function_signature_item name: (identifier) @name)",
+        references: "
+            (call_expression function: (identifier) @name)
+            (call_expression function: (scoped_identifier name: (identifier) @name))
+            (generic_function function: (identifier) @name)
+            (generic_function function: (scoped_identifier name: (identifier) @name))
+            (use_declaration argument: (scoped_identifier name: (identifier) @name))
+            (use_list (identifier) @name)
+            (use_list (scoped_identifier name: (identifier) @name))",
+        // Methods are called as `x.name()`, which is not renamed, so they are not functions
+        excludes: "
+            (impl_item body: (declaration_list (function_item name: (identifier) @name)))
+            (trait_item body: (declaration_list (function_item name: (identifier) @name)))
+            (trait_item body: (declaration_list (function_signature_item name: (identifier) @name)))",
+        manual: "",
+    },
+    types: KindQueries {
+        definitions: "
+            (struct_item name: (type_identifier) @name)
+            (enum_item name: (type_identifier) @name)
+            (union_item name: (type_identifier) @name)
+            (type_item name: (type_identifier) @name)
+            (trait_item name: (type_identifier) @name)",
+        references: "
+            (type_identifier) @name
+            (scoped_identifier path: (identifier) @name)
+            (scoped_type_identifier path: (identifier) @name)
+            (tuple_struct_pattern type: (identifier) @name)
+            (use_declaration argument: (scoped_identifier name: (identifier) @name))
+            (use_list (identifier) @name)
+            (use_list (scoped_identifier name: (identifier) @name))",
+        excludes: "",
+        manual: "(call_expression function: (identifier) @name)",
+    },
+    fields: KindQueries {
+        definitions: "(field_declaration name: (field_identifier) @name)",
+        references: "
+            (field_expression field: (field_identifier) @name)
+            (field_initializer field: (field_identifier) @name)
+            (field_pattern name: (field_identifier) @name)",
+        excludes: "(call_expression function: (field_expression field: (field_identifier) @name))",
+        manual: "
+            (shorthand_field_initializer (identifier) @name)
+            (field_pattern name: (shorthand_field_identifier) @name)",
+    },
+    locals: "
+        (let_declaration pattern: (identifier) @name)
+        (parameter pattern: (identifier) @name)
+        (closure_parameters (identifier) @name)",
+    scopes: &["block", "function_item", "closure_expression"],
+};
+
+const PYTHON: RenameSpec = RenameSpec {
+    language: "Python",
+    functions: KindQueries {
+        definitions: "(function_definition name: (identifier) @name)",
+        references: "
+            (call function: (identifier) @name)
+            (decorator (identifier) @name)
+            (import_from_statement name: (dotted_name (identifier) @name))
+            (import_from_statement name: (aliased_import name: (dotted_name (identifier) @name)))",
+        excludes: "",
+        // `x.name()` may call a method or another module's function, such as `json.load`
+        manual: "(call function: (attribute attribute: (identifier) @name))",
+    },
+    types: KindQueries {
+        definitions: "(class_definition name: (identifier) @name)",
+        references: "
+            (call function: (identifier) @name)
+            (attribute object: (identifier) @name)
+            (class_definition superclasses: (argument_list (identifier) @name))
+            (type (identifier) @name)
+            (import_from_statement name: (dotted_name (identifier) @name))
+            (import_from_statement name: (aliased_import name: (dotted_name (identifier) @name)))",
+        excludes: "",
+        manual: "",
+    },
+    fields: KindQueries {
+        definitions: "
+            (assignment
+              left: (attribute object: (identifier) @self attribute: (identifier) @name)
+              (#eq? @self \"self\"))
+            (class_definition body: (block (expression_statement (assignment left: (identifier) @name))))",
+        references: "(attribute attribute: (identifier) @name)",
+        excludes: "(call function: (attribute attribute: (identifier) @name))",
+        manual: "",
+    },
+    locals: "
+        (assignment left: (identifier) @name)
+        (parameters (identifier) @name)
+        (default_parameter name: (identifier) @name)
+        (typed_parameter (identifier) @name)
+        (typed_default_parameter name: (identifier) @name)
+        (for_statement left: (identifier) @name)",
+    scopes: &["module", "class_definition", "function_definition", "lambda"],
+};
+
+const JAVASCRIPT_FUNCTIONS: KindQueries = KindQueries {
+    definitions: "
+        (function_declaration name: (identifier) @name)
+        (generator_function_declaration name: (identifier) @name)
+        (method_definition name: (property_identifier) @name)
+        (variable_declarator name: (identifier) @name value: [(arrow_function) (function_expression)])",
+    references: "
+        (call_expression function: (identifier) @name)
+        (import_specifier name: (identifier) @name)
+        (export_specifier name: (identifier) @name)",
+    excludes: "",
+    manual: "(call_expression function: (member_expression property: (property_identifier) @name))",
+};
+
+const JAVASCRIPT: RenameSpec = RenameSpec {
+    language: "JavaScript",
+    functions: JAVASCRIPT_FUNCTIONS,
+    types: KindQueries {
+        definitions: "(class_declaration name: (identifier) @name)",
+        references: "
+            (new_expression constructor: (identifier) @name)
+            (class_heritage (identifier) @name)
+            (member_expression object: (identifier) @name)
+            (import_specifier name: (identifier) @name)
+            (export_specifier name: (identifier) @name)",
+        excludes: "",
+        manual: "",
+    },
+    fields: KindQueries {
+        definitions: "(field_definition property: (property_identifier) @name)",
+        references: "(member_expression property: (property_identifier) @name)",
+        excludes: "(call_expression function: (member_expression property: (property_identifier) @name))",
+        manual: "",
+    },
+    locals: "
+        (variable_declarator name: (identifier) @name)
+        (formal_parameters (identifier) @name)
+        (formal_parameters (assignment_pattern left: (identifier) @name))",
+    scopes: &[
+        "program",
+        "statement_block",
+        "function_declaration",
+        "function_expression",
+        "arrow_function",
+        "method_definition",
+    ],
+};
+
+const TYPESCRIPT_TYPES: KindQueries = KindQueries {
+    definitions: "
+        (class_declaration name: (type_identifier) @name)
+        (abstract_class_declaration name: (type_identifier) @name)
+        (interface_declaration name: (type_identifier) @name)
+        (type_alias_declaration name: (type_identifier) @name)
+        (enum_declaration name: (identifier) @name)",
+    references: "
+        (type_identifier) @name
+        (new_expression constructor: (identifier) @name)
+        (extends_clause value: (identifier) @name)
+        (member_expression object: (identifier) @name)
+        (import_specifier name: (identifier) @name)
+        (export_specifier name: (identifier) @name)",
+    excludes: "",
+    manual: "",
+};
+
+const TYPESCRIPT_FIELDS: KindQueries = KindQueries {
+    definitions: "
+        (public_field_definition name: (property_identifier) @name)
+        (property_signature name: (property_identifier) @name)",
+    references: "(member_expression property: (property_identifier) @name)",
+    excludes: "(call_expression function: (member_expression property: (property_identifier) @name))",
+    manual: "",
+};
+
+const TYPESCRIPT_LOCALS: &str = "
+    (variable_declarator name: (identifier) @name)
+    (required_parameter pattern: (identifier) @name)
+    (optional_parameter pattern: (identifier) @name)";
+
+const TYPESCRIPT: RenameSpec = RenameSpec {
+    language: "TypeScript",
+    functions: JAVASCRIPT_FUNCTIONS,
+    types: TYPESCRIPT_TYPES,
+    fields: TYPESCRIPT_FIELDS,
+    locals: TYPESCRIPT_LOCALS,
+    scopes: JAVASCRIPT.scopes,
+};
+
+const TSX: RenameSpec = RenameSpec {
+    language: "TSX",
+    ..TYPESCRIPT
+};
+
+const GO: RenameSpec = RenameSpec {
+    language: "Go",
+    functions: KindQueries {
+        definitions: "
+            (function_declaration name: (identifier) @name)
+            (method_declaration name: (field_identifier) @name)
+            (method_elem name: (field_identifier) @name)",
+        references: "(call_expression function: (identifier) @name)",
+        excludes: "",
+        // `x.Name()` may call a method or another package's function, such as `strings.Split`
+        manual: "(call_expression function: (selector_expression field: (field_identifier) @name))",
+    },
+    types: KindQueries {
+        definitions: "(type_spec name: (type_identifier) @name)",
+        references: "(type_identifier) @name",
+        excludes: "",
+        manual: "",
+    },
+    fields: KindQueries {
+        definitions: "(field_declaration name: (field_identifier) @name)",
+        references: "
+            (selector_expression field: (field_identifier) @name)
+            (keyed_element . (literal_element (identifier) @name))",
+        excludes: "(call_expression function: (selector_expression field: (field_identifier) @name))",
+        manual: "",
+    },
+    locals: "
+        (short_var_declaration left: (expression_list (identifier) @name))
+        (var_spec name: (identifier) @name)
+        (parameter_declaration name: (identifier) @name)
+        (range_clause left: (expression_list (identifier) @name))",
+    scopes: &[
+        "block",
+        "function_declaration",
+        "method_declaration",
+        "func_literal",
+    ],
+};
+
+const C: RenameSpec = RenameSpec {
+    language: "C",
+    functions: KindQueries {
+        definitions: "(function_declarator declarator: (identifier) @name)",
+        references: "(call_expression function: (identifier) @name)",
+        excludes: "",
+        manual: "",
+    },
+    types: KindQueries {
+        definitions: "
+            (struct_specifier name: (type_identifier) @name body: (_))
+            (union_specifier name: (type_identifier) @name body: (_))
+            (enum_specifier name: (type_identifier) @name body: (_))
+            (type_definition declarator: (type_identifier) @name)",
+        references: "(type_identifier) @name",
+        excludes: "",
+        manual: "",
+    },
+    fields: KindQueries {
+        definitions: "(field_declaration declarator: (field_identifier) @name)",
+        references: "
+            (field_expression field: (field_identifier) @name)
+            (field_designator (field_identifier) @name)",
+        excludes: "",
+        manual: "",
+    },
+    locals: "
+        (init_declarator declarator: (identifier) @name)
+        (declaration declarator: (identifier) @name)
+        (parameter_declaration declarator: (identifier) @name)",
+    scopes: &["compound_statement", "function_definition"],
+};
+
+const RENAME_SPECS: &[RenameSpec] = &[RUST, PYTHON, JAVASCRIPT, TYPESCRIPT, TSX, GO, C];
+
+/// One place where the symbol's name appears.
+#[derive(Debug, Clone)]
+pub struct Occurrence {
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub position: SerializablePoint,
+    pub is_definition: bool,
+    node_kind: &'static str,
+}
+
+/// A reason the rename cannot be applied safely.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub position: SerializablePoint,
+    pub message: String,
+}
+
+/// What a rename would change in one file, and what would break.
+#[derive(Debug, Default)]
+pub struct FileAnalysis {
+    /// Definitions and references of the old name, in source order.
+    pub occurrences: Vec<Occurrence>,
+    /// Definitions of the same kind that already use the new name.
+    pub collisions: Vec<Occurrence>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl FileAnalysis {
+    pub fn defines_symbol(&self) -> bool {
+        self.occurrences.iter().any(|o| o.is_definition)
+    }
+}
+
+struct Local {
+    name: String,
+    start_byte: usize,
+    scope: Option<std::ops::Range<usize>>,
+    position: SerializablePoint,
+}
+
+/// Renames a function, type or field in files of one language.
+pub struct Renamer {
+    lang_name: String,
+    language: Language,
+    kind: SymbolKind,
+    old: String,
+    new: String,
+    definitions: Query,
+    references: Query,
+    excludes: Option<Query>,
+    manual: Option<Query>,
+    locals: Query,
+    scopes: &'static [&'static str],
+}
+
+impl Renamer {
+    pub fn new(lang_name: &str, kind: SymbolKind, old: &str, new: &str) -> Result<Self> {
+        let lang =
+            languages::find_spec(lang_name).ok_or_else(|| languages::unsupported(lang_name))?;
+        let spec = RENAME_SPECS
+            .iter()
+            .find(|s| s.language == lang.name)
+            .ok_or_else(|| {
+                let supported: Vec<&str> = RENAME_SPECS.iter().map(|s| s.language).collect();
+                anyhow!(
+                    "Renaming is not supported for {}. Supported languages: {}",
+                    lang.name,
+                    supported.join(", ")
+                )
+            })?;
+        for name in [old, new] {
+            if !is_identifier(name) {
+                return Err(anyhow!("'{}' is not a valid identifier", name));
+            }
+        }
+        if old == new {
+            return Err(anyhow!("The new name is the same as the old one"));
+        }
+
+        let language = lang.language()?;
+        let queries = match kind {
+            SymbolKind::Function => &spec.functions,
+            SymbolKind::Type => &spec.types,
+            SymbolKind::Field => &spec.fields,
+        };
+        let compile = |source: &str| -> Result<Option<Query>> {
+            if source.trim().is_empty() {
+                return Ok(None);
+            }
+            Query::new(&language, source)
+                .map(Some)
+                .with_context(|| format!("Invalid {} rename query for {}", kind, lang.name))
+        };
+        let required = |source: &str| -> Result<Query> {
+            compile(source)?.ok_or_else(|| anyhow!("Missing {} rename query", kind))
+        };
+
+        Ok(Self {
+            lang_name: lang_name.to_string(),
+            kind,
+            old: old.to_string(),
+            new: new.to_string(),
+            definitions: required(queries.definitions)?,
+            references: required(queries.references)?,
+            excludes: compile(queries.excludes)?,
+            manual: compile(queries.manual)?,
+            locals: required(spec.locals)?,
+            scopes: spec.scopes,
+            language,
+        })
+    }
+
+    /// Finds the occurrences of the old name and the conflicts a rename would cause.
+    pub fn analyze(&self, source: &str) -> Result<FileAnalysis> {
+        let mut parser = Parser::new();
+        parser
+            .set_language(&self.language)
+            .context("Error loading language into parser")?;
+        let tree = parser
+            .parse(source, None)
+            .ok_or_else(|| anyhow!("Failed to parse source"))?;
+
+        let excluded: HashSet<usize> = self
+            .excludes
+            .iter()
+            .flat_map(|q| captured_names(q, &tree, source))
+            .map(|n| n.start_byte())
+            .collect();
+
+        let mut analysis = FileAnalysis::default();
+        let mut seen = HashSet::new();
+        for node in captured_names(&self.definitions, &tree, source) {
+            if excluded.contains(&node.start_byte()) {
+                continue;
+            }
+            let text = node_text(node, source);
+            if text == self.old && seen.insert(node.start_byte()) {
+                analysis.occurrences.push(occurrence(node, true));
+            } else if text == self.new {
+                analysis.collisions.push(occurrence(node, true));
+            }
+        }
+
+        let locals = self.locals(&tree, source, &seen);
+
+        for node in captured_names(&self.references, &tree, source) {
+            if node_text(node, source) != self.old
+                || excluded.contains(&node.start_byte())
+                || !seen.insert(node.start_byte())
+            {
+                continue;
+            }
+            if is_bare_name(node) {
+                if find_local(&locals, &self.old, node).is_some() {
+                    // The name refers to a local variable, not to the symbol
+                    continue;
+                }
+                if let Some(local) = find_local(&locals, &self.new, node) {
+                    analysis.conflicts.push(Conflict {
+                        position: node.start_position().into(),
+                        message: format!(
+                            "After renaming, this use would refer to the local '{}' declared on line {}",
+                            self.new,
+                            local.position.row + 1
+                        ),
+                    });
+                }
+            }
+            analysis.occurrences.push(occurrence(node, false));
+        }
+
+        for node in self
+            .manual
+            .iter()
+            .flat_map(|q| captured_names(q, &tree, source))
+        {
+            if node_text(node, source) == self.old {
+                let message = if is_bare_name(node) {
+                    format!(
+                        "This call of '{}' may construct a tuple struct or call a function, so it has to be renamed by hand",
+                        self.old
+                    )
+                } else if let Some(qualifier) = qualifier(node) {
+                    format!(
+                        "This call of '{}' on '{}' may not refer to the {}, so it has to be renamed by hand",
+                        self.old,
+                        node_text(qualifier, source),
+                        self.kind
+                    )
+                } else {
+                    format!(
+                        "This shorthand use of '{}' has to be expanded before renaming",
+                        self.old
+                    )
+                };
+                analysis.conflicts.push(Conflict {
+                    position: node.start_position().into(),
+                    message,
+                });
+            }
+        }
+
+        for collision in &analysis.collisions {
+            analysis.conflicts.push(Conflict {
+                position: collision.position,
+                message: format!("A {} named '{}' already exists", self.kind, self.new),
+            });
+        }
+
+        analysis.occurrences.sort_by_key(|o| o.start_byte);
+        Ok(analysis)
+    }
+
+    /// Rewrites every occurrence found by [`Renamer::analyze`] and returns the new source.
+    pub fn apply(
+        &self,
+        source: String,
+        analysis: &FileAnalysis,
+    ) -> Result<(String, Vec<Modification>)> {
+        if analysis.occurrences.is_empty() {
+            return Ok((source, Vec::new()));
+        }
+        let kinds: BTreeSet<&str> = analysis.occurrences.iter().map(|o| o.node_kind).collect();
+        let alternatives: Vec<String> = kinds.iter().map(|k| format!("({})", k)).collect();
+        let query = format!(
+            "([{}] @target (#eq? @target \"{}\"))",
+            alternatives.join(" "),
+            self.old
+        );
+        let starts: HashSet<usize> = analysis.occurrences.iter().map(|o| o.start_byte).collect();
+
+        let mut transformer = Transformer::new(source, &self.lang_name)?;
+        let modifications =
+            transformer.apply_with(&query, &self.new, &ApplyOptions::default(), |_, m| {
+                starts.contains(&m.start_byte)
+            })?;
+        Ok((transformer.get_source().to_string(), modifications))
+    }
+
+    /// Collects local bindings, except those that are definitions of the symbol itself.
+    fn locals(&self, tree: &Tree, source: &str, definitions: &HashSet<usize>) -> Vec<Local> {
+        captured_names(&self.locals, tree, source)
+            .into_iter()
+            .filter(|n| !definitions.contains(&n.start_byte()))
+            .map(|node| {
+                let mut scope = node.parent();
+                while let Some(n) = scope {
+                    if self.scopes.contains(&n.kind()) {
+                        break;
+                    }
+                    scope = n.parent();
+                }
+                Local {
+                    name: node_text(node, source).to_string(),
+                    start_byte: node.start_byte(),
+                    scope: scope.map(|s| s.byte_range()),
+                    position: node.start_position().into(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Finds a local named `name` that is visible at `node`: declared before it in an
+/// enclosing scope.
+fn find_local<'a>(locals: &'a [Local], name: &str, node: Node) -> Option<&'a Local> {
+    locals.iter().find(|l| {
+        l.name == name
+            && l.start_byte < node.start_byte()
+            && l.scope
+                .as_ref()
+                .is_none_or(|s| s.contains(&node.start_byte()))
+    })
+}
+
+/// Whether `node` is a bare name that a local variable could shadow: the function of a call,
+/// the class of a `new` expression, or the object of a member access, as in `Old.create()`.
+fn is_bare_name(node: Node) -> bool {
+    node.kind() == "identifier"
+        && node.parent().is_some_and(|p| {
+            ["function", "constructor", "object"]
+                .iter()
+                .filter_map(|field| p.child_by_field_name(field))
+                .any(|f| f.id() == node.id())
+        })
+}
+
+/// The object, module or package that `node` is accessed on, such as `json` in `json.load`.
+fn qualifier(node: Node) -> Option<Node> {
+    let parent = node.parent()?;
+    parent
+        .child_by_field_name("object")
+        .or_else(|| parent.child_by_field_name("operand"))
+        .filter(|q| q.id() != node.id())
+}
+
+fn captured_names<'t>(query: &Query, tree: &'t Tree, source: &str) -> Vec<Node<'t>> {
+    let Some(index) = query.capture_index_for_name("name") else {
+        return Vec::new();
+    };
+    let mut nodes = Vec::new();
+    let mut cursor = QueryCursor::new();
+    let mut matches = cursor.matches(query, tree.root_node(), source.as_bytes());
+    while let Some(m) = matches.next() {
+        nodes.extend(m.nodes_for_capture_index(index));
+    }
+    nodes
+}
+
+fn node_text<'s>(node: Node, source: &'s str) -> &'s str {
+    &source[node.byte_range()]
+}
+
+fn occurrence(node: Node, is_definition: bool) -> Occurrence {
+    Occurrence {
+        start_byte: node.start_byte(),
+        end_byte: node.end_byte(),
+        position: node.start_position().into(),
+        is_definition,
+        node_kind: node.kind(),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename(lang: &str, kind: SymbolKind, source: &str) -> Result<(String, FileAnalysis)> {
+        let renamer = Renamer::new(lang, kind, "old", "new")?;
+        let analysis = renamer.analyze(source)?;
+        let (renamed, _) = renamer.apply(source.to_string(), &analysis)?;
+        Ok((renamed, analysis))
+    }
+
+    #[test]
+    fn test_queries_compile_for_every_language() -> Result<()> {
+        // Languages left out of this build cannot be renamed in
+        for spec in RENAME_SPECS
+            .iter()
+            .filter(|s| languages::find_spec(s.language).is_some())
+        {
+            for kind in [SymbolKind::Function, SymbolKind::Type, SymbolKind::Field] {
+                Renamer::new(spec.language, kind, "a", "b")?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_function_skips_shadowing_locals() -> Result<()> {
+        let source =
+            "use m::old;\nfn old() {}\nfn a() { old(); m::old(); }\nfn b(old: fn()) { old(); }";
+        let (renamed, analysis) = rename("rust", SymbolKind::Function, source)?;
+
+        assert!(analysis.conflicts.is_empty());
+        assert_eq!(
+            renamed,
+            "use m::new;\nfn new() {}\nfn a() { new(); m::new(); }\nfn b(old: fn()) { old(); }"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_function_leaves_methods_alone() -> Result<()> {
+        let source = "fn old() {}\nimpl S {\n    fn old(&self) {}\n}\nfn a(s: S) { old(); s.old(); s.old::<u8>(); }";
+        let (renamed, analysis) = rename("rust", SymbolKind::Function, source)?;
+
+        assert!(analysis.conflicts.is_empty());
+        assert_eq!(
+            renamed,
+            "fn new() {}\nimpl S {\n    fn old(&self) {}\n}\nfn a(s: S) { new(); s.old(); s.old::<u8>(); }"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_type_only_in_type_positions() -> Result<()> {
+        let source = "struct old;\nfn f(x: old) -> Vec<old> { helper(); vec![] }";
+        let (renamed, analysis) = rename("rust", SymbolKind::Type, source)?;
+        assert!(analysis.conflicts.is_empty());
+        assert_eq!(
+            renamed,
+            "struct new;\nfn f(x: new) -> Vec<new> { helper(); vec![] }"
+        );
+
+        // A call by the type's name may be a tuple struct constructor or an unrelated function
+        let source = "struct old(u32);\nfn f() -> old { old(1) }";
+        let (_, analysis) = rename("rust", SymbolKind::Type, source)?;
+        assert_eq!(analysis.conflicts.len(), 1);
+        assert!(analysis.conflicts[0].message.contains("renamed by hand"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_reports_conflicts() -> Result<()> {
+        let source = "fn old() {}\nfn new() {}\nfn a() { let new = 1; old(); }";
+        let (_, analysis) = rename("rust", SymbolKind::Function, source)?;
+
+        let messages: Vec<&str> = analysis
+            .conflicts
+            .iter()
+            .map(|c| c.message.as_str())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("local 'new' declared on line 3"));
+        assert!(messages[1].contains("A function named 'new' already exists"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_field_and_type() -> Result<()> {
+        let source =
+            "struct S { old: u32 }\nfn f(s: S) -> u32 { s.old() + s.old + S { old: 1 }.old }";
+        let (renamed, _) = rename("rust", SymbolKind::Field, source)?;
+        assert_eq!(
+            renamed,
+            "struct S { new: u32 }\nfn f(s: S) -> u32 { s.old() + s.new + S { new: 1 }.new }"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-python")]
+    fn test_rename_python_type() -> Result<()> {
+        let source = "class old:\n    pass\n\nclass B(old):\n    def f(self, x: old) -> old:\n        return old()\n";
+        let (renamed, _) = rename("py", SymbolKind::Type, source)?;
+        assert_eq!(renamed, source.replace("old", "new"));
+
+        // A method with the type's name is a different symbol, and a local could shadow the class
+        let source =
+            "class old:\n    pass\n\ndef f(x):\n    new = 1\n    x.old()\n    return old()\n";
+        let (renamed, analysis) = rename("py", SymbolKind::Type, source)?;
+        assert!(renamed.contains("x.old()"));
+        assert_eq!(analysis.conflicts.len(), 1);
+        assert!(
+            analysis.conflicts[0]
+                .message
+                .contains("local 'new' declared on line 5")
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-python")]
+    fn test_rename_python_function_leaves_qualified_calls() -> Result<()> {
+        let source = "import json\n\ndef old(f):\n    return json.old(f)\n\nold(None)\n";
+        let (renamed, analysis) = rename("py", SymbolKind::Function, source)?;
+        assert!(renamed.contains("json.old(f)"));
+        assert_eq!(analysis.conflicts.len(), 1);
+        assert!(
+            analysis.conflicts[0]
+                .message
+                .contains("This call of 'old' on 'json' may not refer to the function")
+        );
+
+        let renamer = Renamer::new("py", SymbolKind::Function, "load", "read_config")?;
+        let analysis = renamer.analyze("import json\n\ndef load(f):\n    return json.load(f)\n")?;
+        assert!(analysis.conflicts[0].message.contains("on 'json'"));
+        assert!(analysis.occurrences.iter().all(|o| o.is_definition));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lang-go")]
+    fn test_rename_go_function_leaves_qualified_calls() -> Result<()> {
+        let renamer = Renamer::new("go", SymbolKind::Function, "Split", "Cut")?;
+        let source =
+            "package p\n\nfunc Split(s string) []string {\n\treturn strings.Split(s, \",\")\n}\n";
+        let analysis = renamer.analyze(source)?;
+        let (renamed, _) = renamer.apply(source.to_string(), &analysis)?;
+        assert!(renamed.contains("func Cut(s string)"));
+        assert!(renamed.contains("strings.Split(s, \",\")"));
+        assert_eq!(analysis.conflicts.len(), 1);
+        assert!(analysis.conflicts[0].message.contains("on 'strings'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_rejects_invalid_names() {
+        assert!(Renamer::new("rust", SymbolKind::Function, "old", "not valid").is_err());
+        assert!(Renamer::new("json", SymbolKind::Field, "a", "b").is_err());
+    }
+}
@@ -1,5 +1,7 @@
-use anyhow::{Context, Result};
+use crate::graft::{Action, CaptureConstraint, OnError, Overlap};
+use anyhow::{Context, Result, anyhow};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
@@ -15,13 +17,108 @@ pub struct Rule {
     #[serde(default)]
     pub priority: i32,
     pub query: String,
-    pub template: String,
+    /// Not needed for `action = "delete"`.
+    #[serde(default)]
+    pub template: RuleTemplate,
+    /// Overrides `--on-error` for this rule.
+    pub on_error: Option<OnError>,
+    /// Overrides `--overlap` for this rule.
+    pub overlap: Option<Overlap>,
+    /// Overrides `--action` for this rule.
+    pub action: Option<Action>,
+    /// Re-apply the rule until nothing matches: `true`, or a maximum number of iterations.
+    pub repeat: Option<Repeat>,
+    /// Conditions on captures checked after the query matched, keyed by capture name.
+    #[serde(default, rename = "where")]
+    pub constraints: BTreeMap<String, CaptureConstraint>,
+}
+
+/// Either a single template, or a table with one template per `@target.<label>` capture.
+/// In a table, the key `target` holds the template for a plain `@target` capture.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum RuleTemplate {
+    Single(String),
+    PerTarget(BTreeMap<String, String>),
+}
+
+impl Default for RuleTemplate {
+    fn default() -> Self {
+        Self::Single(String::new())
+    }
+}
+
+impl RuleTemplate {
+    /// Splits into the main template and the templates of labeled targets.
+    pub fn split(&self) -> (String, BTreeMap<String, String>) {
+        match self {
+            Self::Single(template) => (template.clone(), BTreeMap::new()),
+            Self::PerTarget(templates) => {
+                let mut labeled = templates.clone();
+                let main = labeled.remove("target").unwrap_or_default();
+                (main, labeled)
+            }
+        }
+    }
+}
+
+/// Iterations used by `repeat = true` and a bare `--repeat`.
+pub const DEFAULT_MAX_ITERATIONS: usize = 100;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum Repeat {
+    Enabled(bool),
+    MaxIterations(usize),
+}
+
+impl Repeat {
+    /// Maximum number of iterations, or `None` if the rule is applied only once.
+    pub fn max_iterations(self) -> Option<usize> {
+        match self {
+            Repeat::Enabled(true) => Some(DEFAULT_MAX_ITERATIONS),
+            Repeat::Enabled(false) => None,
+            Repeat::MaxIterations(n) => Some(n),
+        }
+    }
 }
 
 impl RuleFile {
     pub fn load(path: &Path) -> Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read rule file: {:?}", path))?;
-        toml::from_str(&content).with_context(|| format!("Failed to parse rule file: {:?}", path))
+        let file: Self = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse rule file: {:?}", path))?;
+        for (i, rule) in file.rules.iter().enumerate() {
+            if let Some(Repeat::MaxIterations(0)) = rule.repeat {
+                return Err(anyhow!(
+                    "Invalid repeat 0 for rule '{}' in {:?}. Expected true, false or a number of iterations of at least 1",
+                    rule.name
+                        .clone()
+                        .unwrap_or_else(|| format!("rule #{}", i + 1)),
+                    path
+                ));
+            }
+        }
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_repeat_zero_is_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("rules.toml");
+        fs::write(
+            &path,
+            "[[rules]]\nname = \"noop\"\nlanguage = \"rust\"\nquery = \"(identifier) @target\"\ntemplate = \"x\"\nrepeat = 0\n",
+        )?;
+        let err = RuleFile::load(&path).unwrap_err();
+        assert!(err.to_string().contains("Invalid repeat 0 for rule 'noop'"));
+        Ok(())
     }
 }
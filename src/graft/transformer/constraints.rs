@@ -0,0 +1,265 @@
+use anyhow::{Context, Result, anyhow};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Language, Node, Query, QueryCursor, QueryMatch, Tree};
+
+use super::locals::{Locals, RefersTo, Resolution};
+
+/// Constraints on one capture, checked after the query matched (`[rules.where]`).
+///
+/// Every node captured under the name must satisfy `kind`, `regex`, `inside`, `not_inside` and
+/// `refers_to`.
+/// `count` limits how many nodes were captured, which is useful with quantified captures.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConstraint {
+    pub kind: Option<String>,
+    pub regex: Option<String>,
+    pub count: Option<CountConstraint>,
+    /// Tree-sitter pattern that must match one of the capture's ancestors.
+    pub inside: Option<String>,
+    /// Tree-sitter pattern that must not match any of the capture's ancestors.
+    pub not_inside: Option<String>,
+    /// Tree-sitter pattern matching a definition that the capture must resolve to, using the
+    /// language's locals query. The definition is the node captured as `@definition`, or the
+    /// pattern's node if there is no such capture.
+    pub refers_to: Option<String>,
+}
+
+/// Either an exact count (`count = 2`) or a comparison (`count = ">= 2"`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CountConstraint {
+    Exact(usize),
+    Comparison(String),
+}
+
+/// Name given to the node matched by `inside`/`not_inside`/`refers_to` patterns.
+const ANCESTOR_CAPTURE: &str = "graft_ancestor";
+
+/// Capture that selects the definition within a `refers_to` pattern.
+const DEFINITION_CAPTURE: &str = "definition";
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+struct CompiledConstraint {
+    capture_index: u32,
+    kind: Option<String>,
+    regex: Option<Regex>,
+    count: Option<(Comparison, usize)>,
+    inside: Option<Query>,
+    not_inside: Option<Query>,
+    refers_to: Option<Query>,
+}
+
+/// `where` constraints and `#refers-to?` predicates compiled against a specific query.
+pub(super) struct Constraints {
+    items: Vec<CompiledConstraint>,
+    refers_to: RefersTo,
+    /// Only compiled if something needs references resolved.
+    locals: Option<Locals>,
+}
+
+/// Per-tree state needed to evaluate constraints: the ids of nodes matched by
+/// `inside`/`not_inside`/`refers_to` patterns, per constraint, and resolved references.
+pub(super) struct TreeState {
+    inside: Vec<HashSet<usize>>,
+    not_inside: Vec<HashSet<usize>>,
+    refers_to: Vec<HashSet<usize>>,
+    resolution: Option<Resolution>,
+}
+
+impl Constraints {
+    /// `locals` are the language's locals queries, used if a constraint or predicate needs
+    /// references resolved.
+    pub(super) fn compile(
+        language: &Language,
+        lang_name: &str,
+        locals: &[&str],
+        query: &Query,
+        constraints: &BTreeMap<String, CaptureConstraint>,
+    ) -> Result<Self> {
+        let mut items = Vec::new();
+        for (capture, c) in constraints {
+            let capture_index = query.capture_index_for_name(capture).ok_or_else(|| {
+                anyhow!(
+                    "'where' refers to capture '@{}', which the query does not define",
+                    capture
+                )
+            })?;
+            let regex = c
+                .regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid regex in 'where' for '@{}'", capture))?;
+            let count = c
+                .count
+                .as_ref()
+                .map(parse_count)
+                .transpose()
+                .with_context(|| format!("Invalid count in 'where' for '@{}'", capture))?;
+            let compile_pattern = |pattern: &Option<String>| -> Result<Option<Query>> {
+                pattern
+                    .as_deref()
+                    .map(|p| {
+                        Query::new(language, &format!("{} @{}", p, ANCESTOR_CAPTURE)).with_context(
+                            || format!("Invalid pattern '{}' in 'where' for '@{}'", p, capture),
+                        )
+                    })
+                    .transpose()
+            };
+
+            items.push(CompiledConstraint {
+                capture_index,
+                kind: c.kind.clone(),
+                regex,
+                count,
+                inside: compile_pattern(&c.inside)?,
+                not_inside: compile_pattern(&c.not_inside)?,
+                refers_to: compile_pattern(&c.refers_to)?,
+            });
+        }
+
+        let refers_to = RefersTo::compile(query)?;
+        let locals = if refers_to.is_empty() && items.iter().all(|c| c.refers_to.is_none()) {
+            None
+        } else {
+            Some(Locals::compile(language, lang_name, locals)?)
+        };
+        Ok(Self {
+            items,
+            refers_to,
+            locals,
+        })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.refers_to.is_empty()
+    }
+
+    /// Runs the `inside`/`not_inside`/`refers_to` patterns and resolves references in the
+    /// current tree.
+    pub(super) fn prepare(&self, tree: &Tree, source: &str) -> TreeState {
+        let collect = |pattern: &Option<Query>| {
+            let mut ids = HashSet::new();
+            if let Some(query) = pattern {
+                // A `refers_to` pattern can point at the definition inside it with `@definition`
+                let index = query
+                    .capture_index_for_name(DEFINITION_CAPTURE)
+                    .or_else(|| query.capture_index_for_name(ANCESTOR_CAPTURE))
+                    .unwrap();
+                let mut cursor = QueryCursor::new();
+                let mut matches = cursor.matches(query, tree.root_node(), source.as_bytes());
+                while let Some(m) = matches.next() {
+                    ids.extend(m.nodes_for_capture_index(index).map(|n| n.id()));
+                }
+            }
+            ids
+        };
+        TreeState {
+            inside: self.items.iter().map(|c| collect(&c.inside)).collect(),
+            not_inside: self.items.iter().map(|c| collect(&c.not_inside)).collect(),
+            refers_to: self.items.iter().map(|c| collect(&c.refers_to)).collect(),
+            resolution: self.locals.as_ref().map(|l| l.resolve(tree, source)),
+        }
+    }
+
+    pub(super) fn check(&self, m: &QueryMatch, state: &TreeState, source: &str) -> bool {
+        if let Some(resolution) = &state.resolution
+            && !self.refers_to.check(m, resolution)
+        {
+            return false;
+        }
+        self.items.iter().enumerate().all(|(i, c)| {
+            let nodes: Vec<Node> = m.nodes_for_capture_index(c.capture_index).collect();
+            if let Some((op, n)) = c.count
+                && !compare(nodes.len(), op, n)
+            {
+                return false;
+            }
+            // Without a count, a constraint on a capture that did not match fails the match
+            if nodes.is_empty() {
+                return c.count.is_some();
+            }
+            nodes.iter().all(|node| {
+                let text = node.utf8_text(source.as_bytes()).unwrap_or_default();
+                c.kind.as_deref().is_none_or(|k| node.kind() == k)
+                    && c.regex.as_ref().is_none_or(|r| r.is_match(text))
+                    && (c.inside.is_none() || has_ancestor_in(*node, &state.inside[i]))
+                    && !has_ancestor_in(*node, &state.not_inside[i])
+                    && (c.refers_to.is_none() || resolves_to(*node, state, &state.refers_to[i]))
+            })
+        })
+    }
+}
+
+fn has_ancestor_in(node: Node, ids: &HashSet<usize>) -> bool {
+    if ids.is_empty() {
+        return false;
+    }
+    let mut current = node.parent();
+    while let Some(n) = current {
+        if ids.contains(&n.id()) {
+            return true;
+        }
+        current = n.parent();
+    }
+    false
+}
+
+fn resolves_to(node: Node, state: &TreeState, definitions: &HashSet<usize>) -> bool {
+    state
+        .resolution
+        .as_ref()
+        .and_then(|r| r.definition(node))
+        .is_some_and(|d| definitions.contains(&d))
+}
+
+fn parse_count(count: &CountConstraint) -> Result<(Comparison, usize)> {
+    let expr = match count {
+        CountConstraint::Exact(n) => return Ok((Comparison::Eq, *n)),
+        CountConstraint::Comparison(expr) => expr.trim(),
+    };
+    let operators = [
+        (">=", Comparison::Ge),
+        ("<=", Comparison::Le),
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        (">", Comparison::Gt),
+        ("<", Comparison::Lt),
+        ("=", Comparison::Eq),
+    ];
+    let (op, rest) = operators
+        .iter()
+        .find_map(|(prefix, op)| expr.strip_prefix(prefix).map(|rest| (*op, rest)))
+        .unwrap_or((Comparison::Eq, expr));
+    let n = rest.trim().parse().map_err(|_| {
+        anyhow!(
+            "Expected a number or a comparison like 'count = \">= 2\"', got '{}'",
+            expr
+        )
+    })?;
+    Ok((op, n))
+}
+
+fn compare(actual: usize, op: Comparison, expected: usize) -> bool {
+    match op {
+        Comparison::Eq => actual == expected,
+        Comparison::Ne => actual != expected,
+        Comparison::Lt => actual < expected,
+        Comparison::Le => actual <= expected,
+        Comparison::Gt => actual > expected,
+        Comparison::Ge => actual >= expected,
+    }
+}
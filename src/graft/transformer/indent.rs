@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+/// How lines are indented, detected from a file or a template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum IndentStyle {
+    Tabs,
+    Spaces(usize),
+}
+
+impl Default for IndentStyle {
+    fn default() -> Self {
+        Self::Spaces(4)
+    }
+}
+
+impl IndentStyle {
+    /// Detects the indentation of `text`, or `None` if no line is indented.
+    ///
+    /// Tabs win if more lines start with a tab than with a space. Otherwise the width is the
+    /// most common increase in indentation between consecutive lines, which ignores alignment
+    /// that is not a multiple of the indent.
+    pub(super) fn detect(text: &str) -> Option<Self> {
+        let (mut tabs, mut spaces) = (0, 0);
+        let mut deltas: HashMap<usize, usize> = HashMap::new();
+        let mut previous = 0;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let indent = leading_whitespace(line);
+            if indent.starts_with('\t') {
+                tabs += 1;
+                continue;
+            }
+            if !indent.is_empty() {
+                spaces += 1;
+            }
+            if indent.len() > previous {
+                *deltas.entry(indent.len() - previous).or_default() += 1;
+            }
+            previous = indent.len();
+        }
+
+        if tabs > spaces {
+            return Some(Self::Tabs);
+        }
+        deltas
+            .into_iter()
+            .max_by_key(|&(width, count)| (count, std::cmp::Reverse(width)))
+            .map(|(width, _)| Self::Spaces(width))
+    }
+
+    fn unit(self) -> String {
+        match self {
+            Self::Tabs => "\t".to_string(),
+            Self::Spaces(n) => " ".repeat(n),
+        }
+    }
+
+    /// Rewrites leading whitespace from this style to `to`, keeping the nesting level.
+    /// Spaces that do not make up a full level are kept as alignment.
+    fn convert_indent(self, indent: &str, to: Self) -> String {
+        let (levels, rest) = match self {
+            Self::Tabs => {
+                let tabs = indent.chars().take_while(|&c| c == '\t').count();
+                (tabs, &indent[tabs..])
+            }
+            Self::Spaces(n) => {
+                let spaces = indent.chars().take_while(|&c| c == ' ').count();
+                let levels = spaces / n.max(1);
+                (levels, &indent[levels * n..])
+            }
+        };
+        format!("{}{}", to.unit().repeat(levels), rest)
+    }
+}
+
+pub(super) fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Leading whitespace of the line that contains `byte`.
+pub(super) fn line_indent(source: &str, byte: usize) -> &str {
+    let start = source[..byte].rfind('\n').map_or(0, |i| i + 1);
+    leading_whitespace(&source[start..])
+}
+
+/// Marks a continuation line that starts inside a string, comment or heredoc. Its leading
+/// whitespace is part of the program's text, so re-indenting must leave it alone.
+const VERBATIM: char = '\u{1}';
+
+/// Marks every line after the first for which `starts_in_literal`, given the line's byte offset
+/// in `text`, returns true. The marks are removed again by [`unmark`].
+pub(super) fn mark_verbatim(text: &str, starts_in_literal: impl Fn(usize) -> bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (i, _) in text.match_indices('\n') {
+        out.push_str(&text[last..=i]);
+        if starts_in_literal(i + 1) {
+            out.push(VERBATIM);
+        }
+        last = i + 1;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// Removes the marks added by [`mark_verbatim`].
+pub(super) fn unmark(text: &str) -> String {
+    text.replace(&format!("\n{}", VERBATIM), "\n")
+}
+
+/// Maps every line after the first through `f`, except lines marked as verbatim.
+fn map_continuation_lines(text: &str, mut f: impl FnMut(&str) -> String) -> String {
+    let mut lines = text.split('\n');
+    let mut out = lines.next().unwrap_or_default().to_string();
+    for line in lines {
+        out.push('\n');
+        if line.starts_with(VERBATIM) {
+            out.push_str(line);
+        } else {
+            out.push_str(&f(line));
+        }
+    }
+    out
+}
+
+/// Removes `base` from the start of every line after the first, making the text's
+/// indentation relative to the line it started on. Lines may be whitespace-only because
+/// `text` can be a fragment that continues on the same line.
+pub(super) fn dedent(text: &str, base: &str) -> String {
+    map_continuation_lines(text, |line| {
+        line.strip_prefix(base)
+            .unwrap_or_else(|| line.trim_start_matches([' ', '\t']))
+            .to_string()
+    })
+}
+
+/// Prepends `prefix` to every non-blank line after the first.
+pub(super) fn indent(text: &str, prefix: &str) -> String {
+    if prefix.is_empty() {
+        return text.to_string();
+    }
+    map_continuation_lines(text, |line| {
+        if line.trim().is_empty() {
+            line.to_string()
+        } else {
+            format!("{}{}", prefix, line)
+        }
+    })
+}
+
+/// Converts the indentation of every line after the first from one style to another.
+pub(super) fn convert(text: &str, from: IndentStyle, to: IndentStyle) -> String {
+    if from == to {
+        return text.to_string();
+    }
+    map_continuation_lines(text, |line| {
+        let ws = leading_whitespace(line);
+        format!("{}{}", from.convert_indent(ws, to), &line[ws.len()..])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_style() {
+        assert_eq!(
+            IndentStyle::detect("fn a() {\n\tb();\n\tif c {\n\t\td();\n\t}\n}"),
+            Some(IndentStyle::Tabs)
+        );
+        // The alignment of `y` is not mistaken for the indent width
+        assert_eq!(
+            IndentStyle::detect("fn a() {\n  f(x,\n       y);\n  if b {\n    c();\n  }\n}"),
+            Some(IndentStyle::Spaces(2))
+        );
+        assert_eq!(IndentStyle::detect("a\nb\n"), None);
+    }
+
+    #[test]
+    fn test_convert_dedent_and_indent() {
+        let text = "if x {\n    y();\n      z;\n}";
+        assert_eq!(
+            convert(text, IndentStyle::Spaces(4), IndentStyle::Tabs),
+            "if x {\n\ty();\n\t  z;\n}"
+        );
+        assert_eq!(dedent("{\n        a;\n    }", "    "), "{\n    a;\n}");
+        assert_eq!(indent("a\n\nb", "  "), "a\n\n  b");
+    }
+
+    #[test]
+    fn test_verbatim_lines_are_kept() {
+        // The second line starts inside a string
+        let text = mark_verbatim("f(\"a\n  b\",\n    c)", |offset| offset == 5);
+        let dedented = dedent(&text, "    ");
+        assert_eq!(unmark(&dedented), "f(\"a\n  b\",\nc)");
+        assert_eq!(unmark(&indent(&dedented, "\t")), "f(\"a\n  b\",\n\tc)");
+        assert_eq!(
+            unmark(&convert(
+                &dedented,
+                IndentStyle::Spaces(2),
+                IndentStyle::Tabs
+            )),
+            "f(\"a\n  b\",\nc)"
+        );
+    }
+}
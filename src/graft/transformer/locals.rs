@@ -0,0 +1,181 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Language, Node, Query, QueryCursor, QueryMatch, QueryPredicateArg, Tree};
+
+/// Name of the predicate that compares what two captures resolve to.
+const REFERS_TO: &str = "refers-to?";
+
+/// Resolves references to their definitions with a `locals.scm`-style query.
+///
+/// A definition is visible in its innermost `@local.scope` (or the whole file) from where it
+/// appears onward, and a reference resolves to the closest visible definition with the same
+/// name. As in tree-sitter's highlighter, this is purely lexical: there is no notion of
+/// hoisting or of names imported from elsewhere.
+///
+/// A pattern may also capture the whole statement as `@local.declaration`, which makes its
+/// definitions visible only after the statement ends, so that the `x` on the right of
+/// `let x = x + 1;` still refers to the previous `x`.
+pub(super) struct Locals {
+    query: Query,
+    scope: Option<u32>,
+    reference: Option<u32>,
+    declaration: Option<u32>,
+}
+
+/// The definition every resolved node refers to, by node id, for one tree.
+pub(super) struct Resolution {
+    definitions: HashMap<usize, usize>,
+}
+
+impl Locals {
+    pub(super) fn compile(language: &Language, lang_name: &str, sources: &[&str]) -> Result<Self> {
+        if sources.is_empty() {
+            return Err(anyhow!(
+                "Language '{}' has no locals query, so scopes cannot be resolved",
+                lang_name
+            ));
+        }
+        let query = Query::new(language, &sources.join("\n"))
+            .with_context(|| format!("Invalid locals query for language '{}'", lang_name))?;
+        Ok(Self {
+            scope: query.capture_index_for_name("local.scope"),
+            reference: query.capture_index_for_name("local.reference"),
+            declaration: query.capture_index_for_name("local.declaration"),
+            query,
+        })
+    }
+
+    pub(super) fn resolve(&self, tree: &Tree, source: &str) -> Resolution {
+        let mut scopes = HashSet::new();
+        // Definitions with the byte they are visible from
+        let mut definitions: Vec<(Node, usize)> = Vec::new();
+        let mut references: Vec<Node> = Vec::new();
+
+        let mut cursor = QueryCursor::new();
+        let mut matches = cursor.matches(&self.query, tree.root_node(), source.as_bytes());
+        while let Some(m) = matches.next() {
+            let declaration_end = self
+                .declaration
+                .and_then(|index| m.nodes_for_capture_index(index).next())
+                .map(|n| n.end_byte());
+            for c in m.captures {
+                if Some(c.index) == self.scope {
+                    scopes.insert(c.node.id());
+                } else if Some(c.index) == self.reference {
+                    references.push(c.node);
+                } else if self.query.capture_names()[c.index as usize]
+                    .starts_with("local.definition")
+                {
+                    definitions.push((c.node, declaration_end.unwrap_or(c.node.start_byte())));
+                }
+            }
+        }
+
+        // Definitions by (scope node id, name), in the order they become visible
+        let root = tree.root_node().id();
+        let innermost_scope = |node: Node| {
+            let mut current = node.parent();
+            while let Some(n) = current {
+                if scopes.contains(&n.id()) {
+                    return n.id();
+                }
+                current = n.parent();
+            }
+            root
+        };
+        let mut visible: HashMap<(usize, &str), Vec<(Node, usize)>> = HashMap::new();
+        let mut resolved = HashMap::new();
+        definitions.sort_by_key(|&(_, from)| from);
+        for &(d, from) in &definitions {
+            let name = &source[d.byte_range()];
+            visible
+                .entry((innermost_scope(d), name))
+                .or_default()
+                .push((d, from));
+            resolved.insert(d.id(), d.id());
+        }
+
+        for r in references {
+            if resolved.contains_key(&r.id()) {
+                continue;
+            }
+            let name = &source[r.byte_range()];
+            let mut current = r.parent();
+            while let Some(n) = current {
+                if scopes.contains(&n.id()) || n.id() == root {
+                    let definition = visible.get(&(n.id(), name)).and_then(|defs| {
+                        defs.iter().rev().find(|&&(_, from)| from <= r.start_byte())
+                    });
+                    if let Some((d, _)) = definition {
+                        resolved.insert(r.id(), d.id());
+                        break;
+                    }
+                }
+                current = n.parent();
+            }
+        }
+
+        Resolution {
+            definitions: resolved,
+        }
+    }
+}
+
+impl Resolution {
+    /// The id of the definition `node` refers to; a definition refers to itself.
+    pub(super) fn definition(&self, node: Node) -> Option<usize> {
+        self.definitions.get(&node.id()).copied()
+    }
+}
+
+/// The `#refers-to? @a @b` predicates of a query, per pattern: the nodes captured by `@a` must
+/// resolve to the same definition as those captured by `@b`.
+pub(super) struct RefersTo {
+    per_pattern: Vec<Vec<(u32, u32)>>,
+}
+
+impl RefersTo {
+    pub(super) fn compile(query: &Query) -> Result<Self> {
+        let mut per_pattern = Vec::new();
+        for pattern in 0..query.pattern_count() {
+            let mut pairs = Vec::new();
+            for predicate in query.general_predicates(pattern) {
+                if predicate.operator.as_ref() != REFERS_TO {
+                    continue;
+                }
+                match predicate.args.as_ref() {
+                    [QueryPredicateArg::Capture(a), QueryPredicateArg::Capture(b)] => {
+                        pairs.push((*a, *b))
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "#{} expects two captures, e.g. (#{} @reference @definition)",
+                            REFERS_TO,
+                            REFERS_TO
+                        ));
+                    }
+                }
+            }
+            per_pattern.push(pairs);
+        }
+        Ok(Self { per_pattern })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.per_pattern.iter().all(Vec::is_empty)
+    }
+
+    pub(super) fn check(&self, m: &QueryMatch, resolution: &Resolution) -> bool {
+        self.per_pattern[m.pattern_index].iter().all(|&(a, b)| {
+            let definition = m
+                .nodes_for_capture_index(b)
+                .next()
+                .and_then(|n| resolution.definition(n));
+            definition.is_some()
+                && m.nodes_for_capture_index(a)
+                    .chain(m.nodes_for_capture_index(b))
+                    .all(|n| resolution.definition(n) == definition)
+        })
+    }
+}
@@ -1,29 +1,237 @@
-use super::languages;
+use super::{injections, languages};
 use anyhow::{Context, Result, anyhow};
-use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
 use streaming_iterator::StreamingIterator;
 use tree_sitter::{InputEdit, Language, Parser, Point, Query, QueryCursor, Tree};
 
+mod constraints;
+mod indent;
+mod locals;
+mod template;
+use constraints::Constraints;
+pub use constraints::{CaptureConstraint, CountConstraint};
+use indent::IndentStyle;
+use template::{Capture, Template};
+
 pub struct Transformer {
     source: String,
     parser: Parser,
     tree: Tree,
     language: Language,
+    spec: &'static languages::LanguageSpec,
+    diagnostics: Vec<Diagnostic>,
+    /// For code embedded in a file of another language, the byte ranges of the source the
+    /// parser is restricted to. Edits are kept inside them.
+    included_ranges: Option<Vec<Range<usize>>>,
+}
+
+/// What to do when an edit leaves the tree with a syntax error.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Fail the whole `apply` call (the default).
+    #[default]
+    Abort,
+    /// Revert the offending edit, record a diagnostic and continue with the remaining matches.
+    Skip,
+    /// Keep the offending edit, record a diagnostic and continue.
+    Keep,
+}
+
+impl FromStr for OnError {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "skip" => Ok(Self::Skip),
+            "keep" => Ok(Self::Keep),
+            _ => Err(anyhow!(
+                "Invalid on_error policy '{}'. Expected one of: abort, skip, keep",
+                s
+            )),
+        }
+    }
+}
+
+/// How to resolve matches whose target nodes overlap, such as nested binary expressions.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+    /// Apply the outermost match and skip the ones nested inside it (the default).
+    #[default]
+    Outermost,
+    /// Apply the innermost matches first, then re-query and apply the enclosing ones.
+    Innermost,
+    /// Fail if any two matches overlap.
+    Error,
+}
+
+impl FromStr for Overlap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "outermost" => Ok(Self::Outermost),
+            "innermost" => Ok(Self::Innermost),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow!(
+                "Invalid overlap policy '{}'. Expected one of: outermost, innermost, error",
+                s
+            )),
+        }
+    }
+}
+
+/// How a match's target node is edited.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Replace the target with the template (the default).
+    #[default]
+    Replace,
+    /// Insert the template before the target, on its own line if the target starts a line.
+    InsertBefore,
+    /// Insert the template after the target, on its own line if the target ends a line.
+    InsertAfter,
+    /// Remove the target, along with its trailing comma and its line if that becomes empty.
+    Delete,
+    /// Replace the target with the template, which must contain the target capture once.
+    /// The target's text is kept verbatim.
+    Wrap,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "replace" => Ok(Self::Replace),
+            "insert_before" => Ok(Self::InsertBefore),
+            "insert_after" => Ok(Self::InsertAfter),
+            "delete" => Ok(Self::Delete),
+            "wrap" => Ok(Self::Wrap),
+            _ => Err(anyhow!(
+                "Invalid action '{}'. Expected one of: replace, insert_before, insert_after, delete, wrap",
+                s
+            )),
+        }
+    }
+}
+
+/// Per-rule settings for [`Transformer::apply_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    pub on_error: OnError,
+    /// Only treat errors that the edit newly introduced near the edited lines as failures,
+    /// so files that already contain parse errors can still be rewritten.
+    pub allow_existing_errors: bool,
+    pub overlap: Overlap,
+    pub action: Action,
+    /// Re-run the query on the re-parsed tree until nothing matches, up to this many iterations.
+    pub repeat: Option<usize>,
+    /// Extra conditions on captures, keyed by capture name, checked after the query matched.
+    pub constraints: BTreeMap<String, CaptureConstraint>,
+    /// Templates for `@target.<label>` captures, keyed by label. All targets of a match are
+    /// edited together.
+    pub target_templates: BTreeMap<String, String>,
 }
 
 struct Match {
+    /// Range spanning all targets, used to resolve overlapping matches.
+    start_byte: usize,
+    end_byte: usize,
+    targets: Vec<Target>,
+    captures: Vec<Capture>,
+}
+
+/// A node edited by a match: `@target`, a labeled `@target.<label>`, or the first capture.
+struct Target {
+    name: String,
     start_byte: usize,
     end_byte: usize,
-    start_position: Point,
-    end_position: Point,
-    captures: Vec<(String, String)>,
+}
+
+/// Prefix of captures that name additional targets, each with its own template.
+const LABELED_TARGET_PREFIX: &str = "target.";
+
+/// The parsed templates of a rule: the main one and one per `@target.<label>` capture.
+struct Templates {
+    main: Template,
+    labeled: BTreeMap<String, Template>,
+}
+
+impl Templates {
+    fn parse(query: &Query, template_str: &str, options: &ApplyOptions) -> Result<Self> {
+        let main = Template::parse(template_str)
+            .with_context(|| format!("Failed to parse template: '{}'", template_str))?;
+        main.check_captures(query)?;
+
+        let labels: Vec<&str> = query
+            .capture_names()
+            .iter()
+            .filter_map(|n| n.strip_prefix(LABELED_TARGET_PREFIX))
+            .collect();
+        // Deletions ignore their templates, so labeled targets need none
+        let needs_templates = options.action != Action::Delete;
+        if let Some(label) = labels
+            .iter()
+            .find(|l| needs_templates && !options.target_templates.contains_key(**l))
+        {
+            return Err(anyhow!(
+                "The query captures '@{}{}' but no template is given for '{}'",
+                LABELED_TARGET_PREFIX,
+                label,
+                label
+            ));
+        }
+        let has_target = query.capture_index_for_name("target").is_some();
+        if needs_templates && !labels.is_empty() && !has_target && !template_str.trim().is_empty() {
+            return Err(anyhow!(
+                "The query only has labeled targets, so the main template would never be used. Give one template per '@{}<label>' instead.",
+                LABELED_TARGET_PREFIX
+            ));
+        }
+
+        let mut labeled = BTreeMap::new();
+        for (label, template_str) in &options.target_templates {
+            if !labels.contains(&label.as_str()) {
+                return Err(anyhow!(
+                    "A template is given for '{}' but the query has no '@{}{}' capture",
+                    label,
+                    LABELED_TARGET_PREFIX,
+                    label
+                ));
+            }
+            let template = Template::parse(template_str).with_context(|| {
+                format!(
+                    "Failed to parse template for '{}': '{}'",
+                    label, template_str
+                )
+            })?;
+            template.check_captures(query)?;
+            labeled.insert(label.clone(), template);
+        }
+        Ok(Self { main, labeled })
+    }
+
+    fn for_target(&self, name: &str) -> &Template {
+        name.strip_prefix(LABELED_TARGET_PREFIX)
+            .and_then(|label| self.labeled.get(label))
+            .unwrap_or(&self.main)
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
 pub struct Modification {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
     pub start_byte: usize,
     pub old_end_byte: usize,
     pub new_end_byte: usize,
@@ -33,12 +241,33 @@ pub struct Modification {
     pub replacement: String,
 }
 
+/// A non-fatal problem found while applying a rule, such as an edit skipped by `on_error`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Diagnostic {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    pub start_byte: usize,
+    pub start_position: SerializablePoint,
+    pub message: String,
+}
+
 #[derive(Serialize, Debug, Clone, Copy)]
 pub struct SerializablePoint {
     pub row: usize,
     pub column: usize,
 }
 
+impl From<SerializablePoint> for Point {
+    fn from(p: SerializablePoint) -> Self {
+        Self {
+            row: p.row,
+            column: p.column,
+        }
+    }
+}
+
 impl From<Point> for SerializablePoint {
     fn from(p: Point) -> Self {
         Self {
@@ -50,12 +279,36 @@ impl From<Point> for SerializablePoint {
 
 impl Transformer {
     pub fn new(source: String, lang_name: &str) -> Result<Self> {
-        let language = languages::get_language(lang_name)?;
+        Self::with_ranges(source, lang_name, None)
+    }
+
+    /// Transforms only the code in `ranges` of `source`, such as the ranges of an
+    /// [`injections::Injection`]. Queries see the embedded code as a file of its own, while
+    /// edits, modifications and diagnostics refer to the whole source.
+    pub fn new_embedded(
+        source: String,
+        lang_name: &str,
+        ranges: Vec<Range<usize>>,
+    ) -> Result<Self> {
+        Self::with_ranges(source, lang_name, Some(ranges))
+    }
+
+    fn with_ranges(
+        source: String,
+        lang_name: &str,
+        included_ranges: Option<Vec<Range<usize>>>,
+    ) -> Result<Self> {
+        let spec =
+            languages::find_spec(lang_name).ok_or_else(|| languages::unsupported(lang_name))?;
+        let language = spec.language()?;
 
         let mut parser = Parser::new();
         parser
             .set_language(&language)
             .context("Error loading language into parser")?;
+        if let Some(ranges) = &included_ranges {
+            set_included_ranges(&mut parser, &source, ranges)?;
+        }
 
         // Ensure parsing works
         let tree = parser
@@ -67,6 +320,9 @@ impl Transformer {
             parser,
             tree,
             language,
+            spec,
+            diagnostics: Vec::new(),
+            included_ranges,
         })
     }
 
@@ -74,88 +330,492 @@ impl Transformer {
         &self.source
     }
 
+    /// Returns the diagnostics collected since the last call, leaving none behind.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
     pub fn apply(&mut self, query_str: &str, template_str: &str) -> Result<Vec<Modification>> {
+        self.apply_with(query_str, template_str, &ApplyOptions::default(), |_, _| {
+            true
+        })
+    }
+
+    /// Like [`Transformer::apply`], but asks `accept` about every proposed edit before applying it.
+    ///
+    /// `accept` is called in source order with the current source and the edit as it would be
+    /// applied on its own. Rejected edits are left untouched.
+    ///
+    /// Overlapping matches are resolved according to `options.overlap`, and `options.repeat`
+    /// re-runs the query until a fixpoint. Both can take several rounds; later rounds report
+    /// positions in the source as rewritten by the earlier ones.
+    pub fn apply_with<F>(
+        &mut self,
+        query_str: &str,
+        template_str: &str,
+        options: &ApplyOptions,
+        mut accept: F,
+    ) -> Result<Vec<Modification>>
+    where
+        F: FnMut(&str, &Modification) -> bool,
+    {
         let query = Query::new(&self.language, query_str)
             .with_context(|| format!("Failed to parse query: '{}'. Check if the query syntax matches the language grammar.", query_str))?;
+        let constraints = Constraints::compile(
+            &self.language,
+            self.spec.name,
+            self.spec.locals,
+            &query,
+            &options.constraints,
+        )?;
+        let templates = Templates::parse(&query, template_str, options)?;
+        let max_iterations = options.repeat.unwrap_or(1);
+
+        let mut modifications = Vec::new();
+        // Hashes of every source state seen so far, to detect rules that cycle
+        let mut history = vec![hash_source(&self.source)];
+
+        for iteration in 1..=max_iterations {
+            let applied =
+                self.apply_once(&query, &constraints, &templates, options, &mut accept)?;
+            if applied.is_empty() {
+                break;
+            }
+            modifications.extend(applied);
+
+            let current = hash_source(&self.source);
+            if Some(&current) == history.last() {
+                // The edits did not change the text, so another round would match the same nodes
+                break;
+            }
+            if history.contains(&current) {
+                return Err(anyhow!(
+                    "Rule does not converge: iteration {} recreated the source of an earlier iteration. The template probably re-creates a match it rewrites.",
+                    iteration
+                ));
+            }
+            history.push(current);
+
+            // The last allowed iteration may have reached the fixpoint by itself
+            if iteration == max_iterations
+                && max_iterations > 1
+                && !self.collect_matches(&query, &constraints)?.is_empty()
+            {
+                self.report(
+                    0,
+                    format!(
+                        "Stopped repeating after {} iterations without reaching a fixpoint.",
+                        max_iterations
+                    ),
+                );
+            }
+        }
+
+        Ok(modifications)
+    }
+
+    /// Runs one round of the query: collects matches, resolves overlaps and applies the edits.
+    fn apply_once<F>(
+        &mut self,
+        query: &Query,
+        constraints: &Constraints,
+        templates: &Templates,
+        options: &ApplyOptions,
+        accept: &mut F,
+    ) -> Result<Vec<Modification>>
+    where
+        F: FnMut(&str, &Modification) -> bool,
+    {
+        let mut modifications = Vec::new();
+        // Ranges of outer matches postponed by `Overlap::Innermost`, in current coordinates
+        let mut deferred: Option<Vec<Range<usize>>> = None;
+
+        loop {
+            let mut matches = self.collect_matches(query, constraints)?;
+            if let Some(deferred) = &deferred {
+                matches.retain(|m| deferred.contains(&(m.start_byte..m.end_byte)));
+                for range in deferred {
+                    if !matches
+                        .iter()
+                        .any(|m| m.start_byte == range.start && m.end_byte == range.end)
+                    {
+                        self.report(
+                            range.start,
+                            format!(
+                                "Skipped match at byte {} because it no longer matches after rewriting the matches nested inside it.",
+                                range.start
+                            ),
+                        );
+                    }
+                }
+            }
+
+            if matches.is_empty() {
+                break;
+            }
+
+            let (selected, postponed) = self.resolve_overlaps(matches, templates, options)?;
+            let applied = self.apply_matches(selected, templates, options, accept)?;
+
+            if postponed.is_empty() {
+                modifications.extend(applied);
+                break;
+            }
 
+            // Edits were applied bottom-up, so map the postponed ranges in the same order
+            deferred = Some(
+                postponed
+                    .iter()
+                    .filter_map(|m| {
+                        applied
+                            .iter()
+                            .try_fold(m.start_byte..m.end_byte, |r, edit| {
+                                shift_range(
+                                    &r,
+                                    edit.start_byte,
+                                    edit.old_end_byte,
+                                    edit.new_end_byte,
+                                )
+                            })
+                    })
+                    .collect(),
+            );
+            modifications.extend(applied);
+        }
+
+        Ok(modifications)
+    }
+
+    /// Runs the query and keeps the matches that satisfy the rule's `where` constraints.
+    fn collect_matches(&self, query: &Query, constraints: &Constraints) -> Result<Vec<Match>> {
         let mut cursor = QueryCursor::new();
         let mut matches = Vec::new();
+        let state =
+            (!constraints.is_empty()).then(|| constraints.prepare(&self.tree, &self.source));
 
-        // 1. Collect all matches first
-        {
-            let mut query_matches =
-                cursor.matches(&query, self.tree.root_node(), self.source.as_bytes());
-            while let Some(m) = query_matches.next() {
-                let target_idx = query.capture_index_for_name("target");
-                let target_node = if let Some(idx) = target_idx {
-                    m.nodes_for_capture_index(idx).next()
-                } else {
-                    m.captures.first().map(|c| c.node)
-                };
+        let has_targets = query
+            .capture_names()
+            .iter()
+            .any(|n| *n == "target" || n.starts_with(LABELED_TARGET_PREFIX));
 
-                if let Some(node) = target_node {
-                    let mut captures = Vec::new();
-                    for capture in m.captures {
-                        let capture_name =
-                            query.capture_names()[capture.index as usize].to_string();
-                        let capture_text =
-                            capture.node.utf8_text(self.source.as_bytes())?.to_string();
-                        captures.push((capture_name, capture_text));
+        let mut query_matches =
+            cursor.matches(query, self.tree.root_node(), self.source.as_bytes());
+        while let Some(m) = query_matches.next() {
+            if let Some(state) = &state
+                && !constraints.check(m, state, &self.source)
+            {
+                continue;
+            }
+            let targets: Vec<Target> = m
+                .captures
+                .iter()
+                .filter(|c| {
+                    let name = query.capture_names()[c.index as usize];
+                    name == "target" || name.starts_with(LABELED_TARGET_PREFIX)
+                })
+                .chain(m.captures.first().filter(|_| !has_targets))
+                .map(|c| Target {
+                    name: query.capture_names()[c.index as usize].to_string(),
+                    start_byte: c.node.start_byte(),
+                    end_byte: c.node.end_byte(),
+                })
+                .fold(Vec::new(), |mut targets: Vec<Target>, t| {
+                    // Only the first node of a quantified target is edited
+                    if !targets.iter().any(|other| other.name == t.name) {
+                        targets.push(t);
                     }
+                    targets
+                });
 
-                    matches.push(Match {
-                        start_byte: node.start_byte(),
-                        end_byte: node.end_byte(),
-                        start_position: node.start_position(),
-                        end_position: node.end_position(),
-                        captures,
-                    });
+            if !targets.is_empty() {
+                // Group the nodes of quantified captures under one name, in source order.
+                // Multi-line text is dedented relative to the line of the first node.
+                let mut captures: Vec<Capture> = Vec::new();
+                let mut previous_end: Vec<usize> = Vec::new();
+                let mut base_indent: Vec<&str> = Vec::new();
+                for capture in m.captures {
+                    let name = query.capture_names()[capture.index as usize];
+                    let text =
+                        &self.marked_text(capture.node.start_byte(), capture.node.end_byte());
+                    match captures.iter().position(|c| c.name == name) {
+                        Some(i) => {
+                            let gap = self
+                                .source
+                                .get(previous_end[i]..capture.node.start_byte())
+                                .unwrap_or_default();
+                            captures[i]
+                                .separators
+                                .push(indent::dedent(gap, base_indent[i]));
+                            captures[i].texts.push(indent::dedent(text, base_indent[i]));
+                            previous_end[i] = capture.node.end_byte();
+                        }
+                        None => {
+                            let base = indent::line_indent(&self.source, capture.node.start_byte());
+                            captures.push(Capture {
+                                name: name.to_string(),
+                                texts: vec![indent::dedent(text, base)],
+                                separators: Vec::new(),
+                            });
+                            previous_end.push(capture.node.end_byte());
+                            base_indent.push(base);
+                        }
+                    }
+                }
+
+                matches.push(Match {
+                    start_byte: targets
+                        .iter()
+                        .map(|t| t.start_byte)
+                        .min()
+                        .unwrap_or_default(),
+                    end_byte: targets.iter().map(|t| t.end_byte).max().unwrap_or_default(),
+                    targets,
+                    captures,
+                });
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Splits matches into the ones to apply now and the outer ones postponed by
+    /// `Overlap::Innermost`. Matches dropped by the policy are reported as diagnostics.
+    ///
+    /// Matches of the same node that would make the same edits, such as the matches of a query
+    /// that captures one target per argument, are applied once without a diagnostic.
+    fn resolve_overlaps(
+        &mut self,
+        mut matches: Vec<Match>,
+        templates: &Templates,
+        options: &ApplyOptions,
+    ) -> Result<(Vec<Match>, Vec<Match>)> {
+        // Outer matches first when several start at the same byte
+        matches.sort_by(|a, b| {
+            a.start_byte
+                .cmp(&b.start_byte)
+                .then(b.end_byte.cmp(&a.end_byte))
+        });
+
+        let style = IndentStyle::detect(&self.source).unwrap_or_default();
+        let mut unique: Vec<Match> = Vec::with_capacity(matches.len());
+        for m in matches {
+            if let Some(prev) = unique.last()
+                && prev.start_byte == m.start_byte
+                && prev.end_byte == m.end_byte
+            {
+                if self.plan_edits(prev, templates, options.action, style)?
+                    != self.plan_edits(&m, templates, options.action, style)?
+                {
+                    self.report(
+                        m.start_byte,
+                        format!(
+                            "Skipped match at byte {} because another match already edits the same node differently.",
+                            m.start_byte
+                        ),
+                    );
                 }
+                continue;
             }
+            unique.push(m);
         }
 
-        if matches.is_empty() {
-            return Ok(Vec::new());
+        let mut selected = Vec::new();
+        let mut postponed = Vec::new();
+        match options.overlap {
+            Overlap::Outermost => {
+                let mut outer: Option<Range<usize>> = None;
+                for m in unique {
+                    match &outer {
+                        Some(r) if m.start_byte < r.end => self.report(
+                            m.start_byte,
+                            format!(
+                                "Skipped match at byte {} because it is nested inside the match at bytes {}..{}.",
+                                m.start_byte, r.start, r.end
+                            ),
+                        ),
+                        _ => {
+                            outer = Some(m.start_byte..m.end_byte);
+                            selected.push(m);
+                        }
+                    }
+                }
+            }
+            Overlap::Innermost => {
+                let nested: Vec<bool> = unique
+                    .iter()
+                    .enumerate()
+                    .map(|(i, m)| {
+                        unique[i + 1..]
+                            .iter()
+                            .take_while(|n| n.start_byte < m.end_byte)
+                            .any(|n| n.end_byte <= m.end_byte)
+                    })
+                    .collect();
+                for (m, has_nested) in unique.into_iter().zip(nested) {
+                    if has_nested {
+                        postponed.push(m);
+                    } else {
+                        selected.push(m);
+                    }
+                }
+            }
+            Overlap::Error => {
+                for pair in unique.windows(2) {
+                    if pair[1].start_byte < pair[0].end_byte {
+                        return Err(anyhow!(
+                            "Overlapping matches at bytes {}..{} and {}..{}. Use an overlap policy of 'outermost' or 'innermost' to resolve them.",
+                            pair[0].start_byte,
+                            pair[0].end_byte,
+                            pair[1].start_byte,
+                            pair[1].end_byte
+                        ));
+                    }
+                }
+                selected = unique;
+            }
         }
 
-        // 2. Sort matches by start byte descending (Bottom-Up)
-        matches.sort_by(|a, b| b.start_byte.cmp(&a.start_byte));
+        Ok((selected, postponed))
+    }
+
+    /// Expands the templates for each match, asks `accept` about its edits in source order,
+    /// and applies the accepted matches bottom-up with incremental re-parsing and validation.
+    ///
+    /// The edits of one match (one per target) are accepted, validated and reverted together.
+    fn apply_matches<F>(
+        &mut self,
+        matches: Vec<Match>,
+        templates: &Templates,
+        options: &ApplyOptions,
+        accept: &mut F,
+    ) -> Result<Vec<Modification>>
+    where
+        F: FnMut(&str, &Modification) -> bool,
+    {
+        let style = IndentStyle::detect(&self.source).unwrap_or_default();
+        let origin = Point { row: 0, column: 0 };
+        let mut groups = Vec::new();
+        let mut previous_end = 0;
+        for m in &matches {
+            let mut group = Vec::new();
+            for target in &m.targets {
+                let template = templates.for_target(&target.name);
+                let (mut start_byte, mut end_byte, replacement) =
+                    self.plan_edit(m, target, template, options.action, style)?;
+                // Deletions extended over separators must not overlap the previous edit
+                if start_byte < previous_end && start_byte != target.start_byte {
+                    start_byte = previous_end.min(end_byte);
+                    // If that left whole lines to delete, take their line break too
+                    let line_start = start_byte == 0 || self.source[..start_byte].ends_with('\n');
+                    if line_start && self.source[end_byte..].starts_with('\n') {
+                        end_byte += 1;
+                    }
+                }
+                let start_position = calculate_new_position(origin, &self.source[..start_byte]);
+                let old_end_position = calculate_new_position(origin, &self.source[..end_byte]);
+                group.push(Modification {
+                    filename: None,
+                    rule: None,
+                    start_byte,
+                    old_end_byte: end_byte,
+                    new_end_byte: start_byte + replacement.len(),
+                    start_position: start_position.into(),
+                    old_end_position: old_end_position.into(),
+                    new_end_position: calculate_new_position(start_position, &replacement).into(),
+                    replacement,
+                });
+            }
+            group.sort_by_key(|e| e.start_byte);
+            if let Some(pair) = group
+                .windows(2)
+                .find(|p| p[0].old_end_byte > p[1].start_byte)
+            {
+                return Err(anyhow!(
+                    "Targets of the same match overlap at bytes {}..{} and {}..{}",
+                    pair[0].start_byte,
+                    pair[0].old_end_byte,
+                    pair[1].start_byte,
+                    pair[1].old_end_byte
+                ));
+            }
+
+            let outside = self.included_ranges.as_ref().is_some_and(|ranges| {
+                !group.iter().all(|e| {
+                    ranges
+                        .iter()
+                        .any(|r| r.start <= e.start_byte && e.old_end_byte <= r.end)
+                })
+            });
+            if outside {
+                self.report(
+                    m.start_byte,
+                    format!(
+                        "Skipped match at byte {} because its edit would reach outside the embedded code.",
+                        m.start_byte
+                    ),
+                );
+                continue;
+            }
+
+            if group.iter().all(|e| accept(&self.source, e)) {
+                previous_end = group.last().map_or(previous_end, |e| e.old_end_byte);
+                groups.push(group);
+            }
+        }
 
-        // 3. Apply edits
-        let template_regex = Regex::new(r"\$\{(\w+)\}").unwrap();
         let mut modifications = Vec::new();
 
-        for m in matches {
-            let replacement = self.expand_template(template_str, &m.captures, &template_regex)?;
-
-            // Calculate InputEdit
-            let start_byte = m.start_byte;
-            let old_end_byte = m.end_byte;
-            let new_end_byte = start_byte + replacement.len();
-
-            let start_position = m.start_position;
-            let old_end_position = m.end_position;
-
-            // Calculate new end position
-            let new_end_position = calculate_new_position(start_position, &replacement);
-
-            let edit = InputEdit {
-                start_byte,
-                old_end_byte,
-                new_end_byte,
-                start_position,
-                old_end_position,
-                new_end_position,
+        // Matches do not overlap, so applying them (and their edits) from the bottom up keeps
+        // the byte offsets of everything that is still pending valid
+        for group in groups.into_iter().rev() {
+            // Keep what is needed to revert these edits if they turn out to be invalid
+            let baseline = if options.allow_existing_errors {
+                self.error_regions()
+            } else {
+                Vec::new()
             };
+            let had_error = !options.allow_existing_errors && self.tree.root_node().has_error();
+            let previous_tree = self.tree.clone();
+            let previous_ranges = self.included_ranges.clone();
 
-            // Apply to Tree
-            self.tree.edit(&edit);
+            let mut applied = Vec::new();
+            for modification in group.iter().rev() {
+                let start_position = modification.start_position.into();
+                let edit = InputEdit {
+                    start_byte: modification.start_byte,
+                    old_end_byte: modification.old_end_byte,
+                    new_end_byte: modification.new_end_byte,
+                    start_position,
+                    old_end_position: modification.old_end_position.into(),
+                    new_end_position: calculate_new_position(
+                        start_position,
+                        &modification.replacement,
+                    ),
+                };
+                let previous_text =
+                    self.source[modification.start_byte..modification.old_end_byte].to_string();
 
-            // Apply to Source
-            self.source
-                .replace_range(start_byte..old_end_byte, &replacement);
+                self.tree.edit(&edit);
+                self.source.replace_range(
+                    modification.start_byte..modification.old_end_byte,
+                    &modification.replacement,
+                );
+                if let Some(ranges) = &mut self.included_ranges {
+                    injections::shift_ranges(
+                        ranges,
+                        edit.start_byte,
+                        edit.old_end_byte,
+                        edit.new_end_byte,
+                    );
+                }
+                applied.push((edit, previous_text));
+            }
+            let start_byte = group[0].start_byte;
 
             // Incremental Parse
+            if let Some(ranges) = &self.included_ranges {
+                set_included_ranges(&mut self.parser, &self.source, ranges)?;
+            }
             let new_tree = self.parser.parse(&self.source, Some(&self.tree));
 
             if let Some(t) = new_tree {
@@ -168,87 +828,364 @@ impl Transformer {
             }
 
             // Validation: check if resulting source is valid
-            if self.tree.root_node().has_error() {
-                let error_info = self.find_error_context();
-                let error_msg = format!(
-                    "Transformation resulted in syntax error after applying template at byte {}.\n{}",
-                    start_byte, error_info
-                );
+            let error_info = if options.allow_existing_errors {
+                let edits: Vec<InputEdit> = applied.iter().map(|(edit, _)| *edit).collect();
+                self.new_error_near(&baseline, &edits)
+                    .map(|(range, position)| self.describe_error(range, position))
+            } else if self.tree.root_node().has_error() {
+                Some(self.find_error_context())
+            } else {
+                None
+            };
+
+            if let Some(error_info) = error_info {
+                match options.on_error {
+                    OnError::Abort => {
+                        let error_msg = format!(
+                            "Transformation resulted in syntax error after applying template at byte {}.\n{}",
+                            start_byte, error_info
+                        );
 
-                return Err(anyhow!(error_msg));
+                        return Err(anyhow!(error_msg));
+                    }
+                    OnError::Skip => {
+                        // Undo from the top down, so every edit is back at its original offset
+                        for (edit, previous_text) in applied.iter().rev() {
+                            self.source
+                                .replace_range(edit.start_byte..edit.new_end_byte, previous_text);
+                        }
+                        self.tree = previous_tree;
+                        self.included_ranges = previous_ranges;
+                        self.report(
+                            start_byte,
+                            format!(
+                                "Skipped edit at byte {} because it resulted in a syntax error.\n{}",
+                                start_byte, error_info
+                            ),
+                        );
+                        continue;
+                    }
+                    // Only report the edit that first broke the tree, not every one after it
+                    OnError::Keep if !had_error => {
+                        self.report(
+                            start_byte,
+                            format!(
+                                "Kept edit at byte {} although it resulted in a syntax error.\n{}",
+                                start_byte, error_info
+                            ),
+                        );
+                    }
+                    OnError::Keep => {}
+                }
             }
 
-            modifications.push(Modification {
-                filename: None,
-                start_byte,
-                old_end_byte,
-                new_end_byte,
-                start_position: start_position.into(),
-                old_end_position: old_end_position.into(),
-                new_end_position: new_end_position.into(),
-                replacement,
-            });
+            modifications.extend(group.into_iter().rev());
         }
 
         Ok(modifications)
     }
 
-    fn expand_template(
+    /// The edits of every target of `m`, as planned by [`Transformer::plan_edit`].
+    fn plan_edits(
+        &self,
+        m: &Match,
+        templates: &Templates,
+        action: Action,
+        style: IndentStyle,
+    ) -> Result<Vec<(usize, usize, String)>> {
+        m.targets
+            .iter()
+            .map(|t| self.plan_edit(m, t, templates.for_target(&t.name), action, style))
+            .collect()
+    }
+
+    /// Computes the byte range to replace and its replacement for one target of a match.
+    fn plan_edit(
         &self,
-        template: &str,
-        captures: &[(String, String)],
-        regex: &Regex,
-    ) -> Result<String> {
-        let new_text = regex.replace_all(template, |caps: &regex::Captures| {
-            let key = &caps[1];
-            if let Some((_, text)) = captures.iter().find(|(n, _)| n == key) {
-                return text.clone();
+        m: &Match,
+        target: &Target,
+        template: &Template,
+        action: Action,
+        style: IndentStyle,
+    ) -> Result<(usize, usize, String)> {
+        let line_indent = indent::line_indent(&self.source, target.start_byte);
+        let render = || {
+            indent::unmark(&indent::indent(
+                &template.render(&m.captures, style),
+                line_indent,
+            ))
+        };
+        let line_start = self.source[..target.start_byte]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let line_end = self.source[target.end_byte..]
+            .find('\n')
+            .map_or(self.source.len(), |i| target.end_byte + i);
+        let starts_line = self.source[line_start..target.start_byte].trim().is_empty();
+        let ends_line = self.source[target.end_byte..line_end].trim().is_empty();
+
+        Ok(match action {
+            Action::Replace => (target.start_byte, target.end_byte, render()),
+            Action::InsertBefore if starts_line => (
+                target.start_byte,
+                target.start_byte,
+                format!("{}\n{}", render(), line_indent),
+            ),
+            Action::InsertBefore => (target.start_byte, target.start_byte, render()),
+            Action::InsertAfter if ends_line => (
+                target.end_byte,
+                target.end_byte,
+                format!("\n{}{}", line_indent, render()),
+            ),
+            Action::InsertAfter => (target.end_byte, target.end_byte, render()),
+            Action::Delete => {
+                let (start, end) = deletion_range(&self.source, target.start_byte, target.end_byte);
+                (start, end, String::new())
             }
-            format!("${{{}}}", key)
-        });
+            Action::Wrap => {
+                // Render with a placeholder for the target so its text is kept verbatim
+                const PLACEHOLDER: &str = "\u{0}graft-target\u{0}";
+                let mut captures = m.captures.clone();
+                for c in captures.iter_mut().filter(|c| c.name == target.name) {
+                    c.texts = vec![PLACEHOLDER.to_string()];
+                }
+                let rendered = indent::indent(&template.render(&captures, style), line_indent);
+                let Some((before, after)) = rendered.split_once(PLACEHOLDER) else {
+                    return Err(anyhow!(
+                        "The 'wrap' action needs a template that contains '${{{}}}'",
+                        target.name
+                    ));
+                };
+                if after.contains(PLACEHOLDER) {
+                    return Err(anyhow!(
+                        "The 'wrap' action needs a template that contains '${{{}}}' only once",
+                        target.name
+                    ));
+                }
+                // Continuation lines of the target follow the indentation it is wrapped at
+                let inner_indent = indent::leading_whitespace(
+                    &before[before.rfind('\n').map_or(before.len(), |i| i + 1)..],
+                );
+                let extra = inner_indent.strip_prefix(line_indent).unwrap_or_default();
+                let original =
+                    indent::indent(&self.marked_text(target.start_byte, target.end_byte), extra);
+                (
+                    target.start_byte,
+                    target.end_byte,
+                    indent::unmark(&format!("{}{}{}", before, original, after)),
+                )
+            }
+        })
+    }
 
-        Ok(new_text.to_string())
+    /// The source from `start` to `end`, with the lines that start inside a string, comment or
+    /// heredoc marked so that re-indenting keeps them as they are.
+    fn marked_text(&self, start: usize, end: usize) -> String {
+        indent::mark_verbatim(&self.source[start..end], |offset| {
+            let byte = start + offset;
+            let mut node = self.tree.root_node().descendant_for_byte_range(byte, byte);
+            while let Some(n) = node {
+                // A heredoc's terminator line must stay where it is, even though it starts
+                // its own node
+                if languages::is_literal_text(n.kind())
+                    && (n.start_byte() < byte || n.kind().contains("heredoc"))
+                {
+                    return true;
+                }
+                node = n.parent();
+            }
+            false
+        })
+    }
+
+    /// Records a diagnostic for the current source at `start_byte`.
+    fn report(&mut self, start_byte: usize, message: String) {
+        let start_position =
+            calculate_new_position(Point { row: 0, column: 0 }, &self.source[..start_byte]);
+        self.diagnostics.push(Diagnostic {
+            filename: None,
+            rule: None,
+            start_byte,
+            start_position: start_position.into(),
+            message,
+        });
     }
 
     /// Finds context around the first syntax error in the current tree.
     fn find_error_context(&self) -> String {
-        let mut error_node = None;
+        if let Some((range, position)) = self.error_regions().pop() {
+            self.describe_error(range, position)
+        } else {
+            "No specific error location found.".to_string()
+        }
+    }
+
+    /// Collects the byte ranges and start positions of all ERROR and MISSING nodes.
+    fn error_regions(&self) -> Vec<(Range<usize>, Point)> {
+        let mut regions = Vec::new();
         let mut stack = vec![self.tree.root_node()];
         while let Some(node) = stack.pop() {
             if node.has_error() {
                 if node.is_error() || node.is_missing() {
-                    error_node = Some(node);
+                    regions.push((node.start_byte()..node.end_byte(), node.start_position()));
                 }
                 for i in (0..node.child_count()).rev() {
                     stack.push(node.child(i as u32).unwrap());
                 }
             }
         }
+        regions
+    }
 
-        if let Some(node) = error_node {
-            let start = node.start_byte().min(self.source.len());
-            let end = node.end_byte().min(self.source.len()).max(start);
-
-            let line_start = self.source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-            let line_end = self.source[end..]
-                .find('\n')
-                .map(|i| i + end)
-                .unwrap_or(self.source.len());
-            let context = &self.source[line_start..line_end];
-            let offset = start - line_start;
-            let mut pointer = " ".repeat(offset);
-            pointer.push('^');
-            format!(
-                "Error at {}:{}:\n{}\n{}",
-                node.start_position().row + 1,
-                node.start_position().column + 1,
-                context,
-                pointer
-            )
-        } else {
-            "No specific error location found.".to_string()
-        }
+    /// Finds an error region that did not exist in `baseline` (taken before `edits`, which were
+    /// applied in order from the bottom up) and lies on the lines touched by one of the edits.
+    fn new_error_near(
+        &self,
+        baseline: &[(Range<usize>, Point)],
+        edits: &[InputEdit],
+    ) -> Option<(Range<usize>, Point)> {
+        let expected: Vec<Range<usize>> = baseline
+            .iter()
+            .filter_map(|(r, _)| {
+                edits.iter().try_fold(r.clone(), |r, edit| {
+                    shift_range(&r, edit.start_byte, edit.old_end_byte, edit.new_end_byte)
+                })
+            })
+            .collect();
+
+        // Edits applied later sit above earlier ones and shift them
+        let windows: Vec<Range<usize>> = edits
+            .iter()
+            .enumerate()
+            .map(|(i, edit)| {
+                let shift: isize = edits[i + 1..]
+                    .iter()
+                    .map(|e| e.new_end_byte as isize - e.old_end_byte as isize)
+                    .sum();
+                let start = (edit.start_byte as isize + shift) as usize;
+                let end = (edit.new_end_byte as isize + shift) as usize;
+                let window_start = self.source[..start].rfind('\n').map_or(0, |i| i + 1);
+                let window_end = self.source[end..]
+                    .find('\n')
+                    .map_or(self.source.len(), |i| i + end);
+                window_start..window_end
+            })
+            .collect();
+
+        self.error_regions().into_iter().find(|(r, _)| {
+            !expected.contains(r) && windows.iter().any(|w| r.start <= w.end && r.end >= w.start)
+        })
+    }
+
+    fn describe_error(&self, range: Range<usize>, position: Point) -> String {
+        let start = range.start.min(self.source.len());
+        let end = range.end.min(self.source.len()).max(start);
+
+        let line_start = self.source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.source[end..]
+            .find('\n')
+            .map(|i| i + end)
+            .unwrap_or(self.source.len());
+        let context = &self.source[line_start..line_end];
+        let offset = start - line_start;
+        let mut pointer = " ".repeat(offset);
+        pointer.push('^');
+        format!(
+            "Error at {}:{}:\n{}\n{}",
+            position.row + 1,
+            position.column + 1,
+            context,
+            pointer
+        )
+    }
+}
+
+/// Maps a byte range of the source before an edit of `start..old_end` into `start..new_end`
+/// to the source after it. Ranges that enclose the edit grow or shrink with it; ranges that
+/// partially overlap it cannot be mapped.
+fn shift_range(
+    range: &Range<usize>,
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+) -> Option<Range<usize>> {
+    let shift = |byte: usize| byte + new_end - old_end;
+    if range.end <= start {
+        Some(range.clone())
+    } else if range.start >= old_end {
+        Some(shift(range.start)..shift(range.end))
+    } else if range.start <= start && range.end >= old_end {
+        Some(range.start..shift(range.end))
+    } else {
+        None
+    }
+}
+
+/// Restricts `parser` to `ranges` of `source`, leaving out those that edits emptied.
+fn set_included_ranges(parser: &mut Parser, source: &str, ranges: &[Range<usize>]) -> Result<()> {
+    let origin = Point { row: 0, column: 0 };
+    let mut included: Vec<tree_sitter::Range> = ranges
+        .iter()
+        .filter(|r| !r.is_empty())
+        .map(|r| tree_sitter::Range {
+            start_byte: r.start,
+            end_byte: r.end,
+            start_point: calculate_new_position(origin, &source[..r.start]),
+            end_point: calculate_new_position(origin, &source[..r.end]),
+        })
+        .collect();
+    // No ranges at all would mean the whole source
+    if included.is_empty() {
+        let start = ranges.first().map_or(0, |r| r.start);
+        let point = calculate_new_position(origin, &source[..start]);
+        included.push(tree_sitter::Range {
+            start_byte: start,
+            end_byte: start,
+            start_point: point,
+            end_point: point,
+        });
+    }
+    parser
+        .set_included_ranges(&included)
+        .map_err(|e| anyhow!("Invalid ranges of embedded code: {:?}", e))
+}
+
+fn hash_source(source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Extends a deleted range over a trailing comma and over its line if nothing else is left
+/// on it. The last element of a list, with no comma after it, takes the preceding comma
+/// instead.
+fn deletion_range(source: &str, start: usize, end: usize) -> (usize, usize) {
+    let (mut start, mut end) = (start, end);
+    let after = &source[end..];
+    let next = after.trim_start();
+    let closes_list = next.starts_with([')', ']', '}', '>']);
+    if let Some(rest) = next.strip_prefix(',') {
+        // Take the comma and the spaces after it, but not a line break
+        let spaces = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+        end += after.len() - next.len() + 1 + spaces;
+    }
+    let before = source[..start].trim_end();
+    let preceding_comma = (closes_list && before.ends_with(',')).then(|| before.len() - 1);
+
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[end..].find('\n').map_or(source.len(), |i| end + i);
+    if source[line_start..start].trim().is_empty() && source[end..line_end].trim().is_empty() {
+        return match preceding_comma {
+            // Keep the line break that ends the line, which now ends the previous element
+            Some(comma) => (comma, line_end),
+            None => (line_start, (line_end + 1).min(source.len())),
+        };
+    }
+
+    if let Some(comma) = preceding_comma {
+        start = comma;
     }
+    (start, end)
 }
 
 fn calculate_new_position(start: Point, text: &str) -> Point {
@@ -0,0 +1,687 @@
+use super::indent::{self, IndentStyle};
+use anyhow::{Result, anyhow, bail};
+use tree_sitter::Query;
+
+/// A parsed replacement template.
+///
+/// Supported syntax:
+/// - `${name}` substitutes a capture, `${name|upper|trim}` pipes it through filters,
+///   and `${name:-default}` falls back to `default` when the capture is missing or empty, or
+///   is not defined by the query at all. Braces in `default` must be balanced, as in
+///   `${body:-{}}`.
+/// - `${name...}` joins every node of a quantified capture with the separators found between
+///   them in the source; `${name|join(", ")}` uses a custom separator.
+/// - `{% if name %}…{% else %}…{% endif %}` renders a branch depending on whether the capture
+///   is non-empty; `{% if not name %}` inverts the test.
+/// - `{% for item in name %}…{% endfor %}` renders the body once per captured node, optionally
+///   separated with `{% for item in name sep ", " %}`.
+/// - `$$` is a literal `$`.
+///
+/// Lines after the first are indented relative to the line the replacement starts on, and
+/// multi-line captures keep their indentation relative to the line they are inserted on.
+#[derive(Debug)]
+pub(super) struct Template {
+    nodes: Vec<Node>,
+    /// Indentation used by the template text, converted to the file's style when rendering.
+    style: Option<IndentStyle>,
+}
+
+/// The nodes captured under one name in a single match.
+#[derive(Debug, Clone)]
+pub(super) struct Capture {
+    pub name: String,
+    /// Text of each captured node, in source order.
+    pub texts: Vec<String>,
+    /// Source text between consecutive nodes, used by `${name...}`.
+    pub separators: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Capture {
+        name: String,
+        filters: Vec<Filter>,
+        default: Option<String>,
+    },
+    If {
+        name: String,
+        negated: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        item: String,
+        list: String,
+        separator: String,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Filter {
+    Upper,
+    Lower,
+    SnakeCase,
+    CamelCase,
+    Trim,
+    Quote,
+    /// `...`: join with the separator from the source.
+    Spread,
+    Join(String),
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Self> {
+        if let Some(arg) = filter
+            .strip_prefix("join(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            return Ok(Self::Join(parse_string(arg.trim())?));
+        }
+        match filter {
+            "upper" => Ok(Self::Upper),
+            "lower" => Ok(Self::Lower),
+            "snake_case" => Ok(Self::SnakeCase),
+            "camel_case" => Ok(Self::CamelCase),
+            "trim" => Ok(Self::Trim),
+            "quote" => Ok(Self::Quote),
+            _ => Err(anyhow!(
+                "Unknown template filter '{}'. Expected one of: upper, lower, snake_case, camel_case, trim, quote, join(\"sep\")",
+                filter
+            )),
+        }
+    }
+
+    /// Applies the filter to a list value. Text filters work on each element;
+    /// joins collapse the list into one element.
+    fn apply(&self, items: Vec<String>, separators: &[String]) -> Vec<String> {
+        match self {
+            Self::Spread => {
+                let mut joined = String::new();
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        // Reuse the first gap if a filter changed the number of items
+                        let gap = separators.get(i - 1).or(separators.first());
+                        joined.push_str(gap.map_or("", String::as_str));
+                    }
+                    joined.push_str(item);
+                }
+                vec![joined]
+            }
+            Self::Join(sep) => vec![items.join(sep)],
+            _ => items.iter().map(|t| self.apply_text(t)).collect(),
+        }
+    }
+
+    fn apply_text(&self, text: &str) -> String {
+        match self {
+            Self::Upper => text.to_uppercase(),
+            Self::Lower => text.to_lowercase(),
+            Self::SnakeCase => words(text)
+                .iter()
+                .map(|w| w.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            Self::CamelCase => words(text)
+                .iter()
+                .enumerate()
+                .map(|(i, w)| {
+                    let lower = w.to_lowercase();
+                    if i == 0 {
+                        return lower;
+                    }
+                    let mut chars = lower.chars();
+                    chars
+                        .next()
+                        .map(|c| c.to_uppercase().chain(chars).collect())
+                        .unwrap_or_default()
+                })
+                .collect(),
+            Self::Trim => text.trim().to_string(),
+            Self::Quote => format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")),
+            Self::Spread | Self::Join(_) => text.to_string(),
+        }
+    }
+}
+
+/// Splits an identifier into words at separators and case changes
+/// (`parseHTTPResponse` → `parse`, `HTTP`, `Response`).
+fn words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !current.is_empty() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_is_lower) {
+                words.push(std::mem::take(&mut current));
+            }
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+/// Parses a double-quoted string literal with `\"`, `\\`, `\n` and `\t` escapes.
+fn parse_string(literal: &str) -> Result<String> {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid template: expected a quoted string, got '{}'",
+                literal
+            )
+        })?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c @ ('"' | '\\')) => out.push(c),
+            _ => bail!("Invalid template: bad escape in string {}", literal),
+        }
+    }
+    Ok(out)
+}
+
+/// Finds `pattern` in `text`, ignoring occurrences inside double-quoted strings.
+fn find_unquoted(text: &str, pattern: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if text[i..].starts_with(pattern) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Finds the `}` closing the `{` that `text` starts with, skipping balanced braces and
+/// quoted strings in between.
+fn find_closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_unquoted<'a>(mut text: &'a str, pattern: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    while let Some(i) = find_unquoted(text, pattern) {
+        parts.push(&text[..i]);
+        text = &text[i + pattern.len()..];
+    }
+    parts.push(text);
+    parts
+}
+
+impl Template {
+    pub(super) fn parse(template: &str) -> Result<Self> {
+        let mut parser = Parser {
+            input: template,
+            pos: 0,
+        };
+        let (nodes, end) = parser.parse_nodes()?;
+        if let Some(tag) = end {
+            bail!("Invalid template: unexpected '{{% {} %}}'", tag);
+        }
+        Ok(Self {
+            nodes,
+            style: IndentStyle::detect(template),
+        })
+    }
+
+    /// Fails if the template refers to a capture the query does not define, unless it gives
+    /// a default for it.
+    pub(super) fn check_captures(&self, query: &Query) -> Result<()> {
+        check_names(&self.nodes, &mut Vec::new(), query)
+    }
+
+    /// Renders the template for one match. Captures that did not take part in the match
+    /// (for example optional ones) render as empty text.
+    ///
+    /// Capture texts must be dedented relative to their first line. The result is
+    /// indented relative to its own first line, using the file's `style`.
+    pub(super) fn render(&self, captures: &[Capture], style: IndentStyle) -> String {
+        let mut scope: Vec<Capture> = captures.to_vec();
+        let mut out = String::new();
+        let renderer = Renderer {
+            from: self.style.unwrap_or(style),
+            to: style,
+        };
+        renderer.render_nodes(&self.nodes, &mut scope, &mut out);
+        out
+    }
+}
+
+fn check_names(nodes: &[Node], bound: &mut Vec<String>, query: &Query) -> Result<()> {
+    let check = |name: &str, bound: &[String]| {
+        if bound.iter().any(|b| b == name) || query.capture_index_for_name(name).is_some() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "Template refers to undefined capture '{}'. The query defines: {}",
+            name,
+            query
+                .capture_names()
+                .iter()
+                .map(|n| format!("@{}", n))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    };
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            // A capture with a default renders the default when the query does not define it
+            Node::Capture {
+                default: Some(_), ..
+            } => {}
+            Node::Capture { name, .. } => check(name, bound)?,
+            Node::If {
+                name,
+                then,
+                otherwise,
+                ..
+            } => {
+                check(name, bound)?;
+                check_names(then, bound, query)?;
+                check_names(otherwise, bound, query)?;
+            }
+            Node::For {
+                item, list, body, ..
+            } => {
+                check(list, bound)?;
+                bound.push(item.clone());
+                check_names(body, bound, query)?;
+                bound.pop();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks a name up, preferring the innermost loop variable.
+fn lookup<'a>(scope: &'a [Capture], name: &str) -> Option<&'a Capture> {
+    scope.iter().rev().find(|c| c.name == name)
+}
+
+fn is_present(capture: Option<&Capture>) -> bool {
+    capture.is_some_and(|c| c.texts.iter().any(|t| !t.is_empty()))
+}
+
+struct Renderer {
+    from: IndentStyle,
+    to: IndentStyle,
+}
+
+impl Renderer {
+    fn render_nodes(&self, nodes: &[Node], scope: &mut Vec<Capture>, out: &mut String) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(&indent::convert(text, self.from, self.to)),
+                Node::Capture {
+                    name,
+                    filters,
+                    default,
+                } => {
+                    let capture = lookup(scope, name);
+                    if !is_present(capture) {
+                        out.push_str(default.as_deref().unwrap_or_default());
+                        continue;
+                    }
+                    let capture = capture.unwrap();
+                    let items = filters.iter().fold(capture.texts.clone(), |items, f| {
+                        f.apply(items, &capture.separators)
+                    });
+                    // Without a join, a quantified capture inserts its first node
+                    let text = items.first().map_or("", String::as_str);
+                    let line_start = out.rfind('\n').map_or(0, |i| i + 1);
+                    let prefix = indent::leading_whitespace(&out[line_start..]).to_string();
+                    out.push_str(&indent::indent(text, &prefix));
+                }
+                Node::If {
+                    name,
+                    negated,
+                    then,
+                    otherwise,
+                } => {
+                    let branch = if is_present(lookup(scope, name)) != *negated {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    self.render_nodes(branch, scope, out);
+                }
+                Node::For {
+                    item,
+                    list,
+                    separator,
+                    body,
+                } => {
+                    let texts = lookup(scope, list).map(|c| c.texts.clone());
+                    for (i, text) in texts.into_iter().flatten().enumerate() {
+                        if i > 0 {
+                            out.push_str(separator);
+                        }
+                        scope.push(Capture {
+                            name: item.clone(),
+                            texts: vec![text],
+                            separators: Vec::new(),
+                        });
+                        self.render_nodes(body, scope, out);
+                        scope.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    /// Parses nodes until the end of input or a block tag that closes the current block
+    /// (`else`/`endif`/`endfor`), which is returned.
+    fn parse_nodes(&mut self) -> Result<(Vec<Node>, Option<String>)> {
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+        while let Some(c) = self.rest().chars().next() {
+            if self.rest().starts_with("$$") {
+                text.push('$');
+                self.pos += 2;
+            } else if self.rest().starts_with("${") {
+                flush(&mut text, &mut nodes);
+                nodes.push(self.parse_capture()?);
+            } else if self.rest().starts_with("{%") {
+                flush(&mut text, &mut nodes);
+                let tag = self.parse_tag()?;
+                let mut words = tag.split_whitespace();
+                match words.next() {
+                    Some("if") => nodes.push(self.parse_if(&tag)?),
+                    Some("for") => nodes.push(self.parse_for(&tag)?),
+                    Some("else" | "endif" | "endfor") if words.next().is_none() => {
+                        return Ok((nodes, Some(tag)));
+                    }
+                    _ => bail!("Invalid template: unknown tag '{{% {} %}}'", tag),
+                }
+            } else {
+                text.push(c);
+                self.pos += c.len_utf8();
+            }
+        }
+        flush(&mut text, &mut nodes);
+        Ok((nodes, None))
+    }
+
+    fn parse_if(&mut self, tag: &str) -> Result<Node> {
+        let words: Vec<&str> = tag.split_whitespace().collect();
+        let (negated, name) = match words[..] {
+            ["if", "not", name] => (true, name.to_string()),
+            ["if", name] => (false, name.to_string()),
+            _ => bail!(
+                "Invalid template: expected '{{% if name %}}', got '{{% {} %}}'",
+                tag
+            ),
+        };
+        let missing_endif = || {
+            anyhow!(
+                "Invalid template: '{{% {} %}}' is missing '{{% endif %}}'",
+                tag
+            )
+        };
+
+        let (then, end) = self.parse_nodes()?;
+        let otherwise = match end.as_deref() {
+            Some("else") => match self.parse_nodes()? {
+                (otherwise, Some(end)) if end == "endif" => otherwise,
+                _ => return Err(missing_endif()),
+            },
+            Some("endif") => Vec::new(),
+            _ => return Err(missing_endif()),
+        };
+        Ok(Node::If {
+            name,
+            negated,
+            then,
+            otherwise,
+        })
+    }
+
+    /// Parses `{% for item in list %}` or `{% for item in list sep "..." %}` and its body.
+    fn parse_for(&mut self, tag: &str) -> Result<Node> {
+        let invalid = || {
+            anyhow!(
+                "Invalid template: expected '{{% for item in name %}}' or '{{% for item in name sep \", \" %}}', got '{{% {} %}}'",
+                tag
+            )
+        };
+        // The separator literal may contain whitespace, so only split off the words before it
+        let mut rest = tag;
+        let mut parts = Vec::new();
+        while parts.len() < 5 {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            parts.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        let (item, list, separator) = match parts[..] {
+            ["for", item, "in", list] => (item, list, String::new()),
+            ["for", item, "in", list, "sep"] => (item, list, parse_string(rest.trim())?),
+            _ => return Err(invalid()),
+        };
+        let (item, list) = (item.to_string(), list.to_string());
+
+        let (body, end) = self.parse_nodes()?;
+        if end.as_deref() != Some("endfor") {
+            bail!(
+                "Invalid template: '{{% {} %}}' is missing '{{% endfor %}}'",
+                tag
+            );
+        }
+        Ok(Node::For {
+            item,
+            list,
+            separator,
+            body,
+        })
+    }
+
+    /// Parses `${name...|filter:-default}` starting at `${`.
+    fn parse_capture(&mut self) -> Result<Node> {
+        let start = self.pos;
+        let end = find_closing_brace(&self.rest()[1..])
+            .map(|i| i + 1)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid template: unclosed '${{' at byte {}. Use '$$' for a literal '$'",
+                    start
+                )
+            })?;
+        let body = &self.rest()[2..end];
+        self.pos += end + 1;
+
+        let (expr, default) = match find_unquoted(body, ":-") {
+            Some(i) => (&body[..i], Some(body[i + 2..].to_string())),
+            None => (body, None),
+        };
+        let mut parts = split_unquoted(expr, "|").into_iter().map(str::trim);
+        let mut name = parts.next().unwrap_or_default();
+        let mut filters = Vec::new();
+        if let Some(stripped) = name.strip_suffix("...") {
+            name = stripped;
+            filters.push(Filter::Spread);
+        }
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        {
+            bail!("Invalid template: bad capture name in '${{{}}}'", body);
+        }
+        for filter in parts {
+            filters.push(Filter::parse(filter)?);
+        }
+        Ok(Node::Capture {
+            name: name.to_string(),
+            filters,
+            default,
+        })
+    }
+
+    /// Parses `{% ... %}` and returns the trimmed tag contents.
+    fn parse_tag(&mut self) -> Result<String> {
+        let start = self.pos;
+        let end = find_unquoted(self.rest(), "%}")
+            .ok_or_else(|| anyhow!("Invalid template: unclosed '{{%' at byte {}", start))?;
+        let tag = self.rest()[2..end].trim().to_string();
+        self.pos += end + 2;
+        Ok(tag)
+    }
+}
+
+fn flush(text: &mut String, nodes: &mut Vec<Node>) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &Template, captures: &[Capture]) -> String {
+        template.render(captures, IndentStyle::default())
+    }
+
+    fn captures(pairs: &[(&str, &str)]) -> Vec<Capture> {
+        pairs
+            .iter()
+            .map(|(n, t)| Capture {
+                name: n.to_string(),
+                texts: vec![t.to_string()],
+                separators: Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_filters_defaults_and_escapes() -> Result<()> {
+        let template = Template::parse(
+            "${name|snake_case|upper} ${name|camel_case} ${s|trim|quote} ${x:-none} $$x",
+        )?;
+        let rendered = render(
+            &template,
+            &captures(&[("name", "parseHTTPResponse"), ("s", "  say \"hi\" ")]),
+        );
+        assert_eq!(
+            rendered,
+            "PARSE_HTTP_RESPONSE parseHttpResponse \"say \\\"hi\\\"\" none $x"
+        );
+
+        // Defaults can contain balanced braces, and quoted ones
+        let template = Template::parse("fn f() ${body:-{}} ${x:-{ a: {} \"}\" }}")?;
+        assert_eq!(render(&template, &[]), "fn f() {} { a: {} \"}\" }");
+        assert_eq!(
+            render(&template, &captures(&[("body", "{ g(); }")])),
+            "fn f() { g(); } { a: {} \"}\" }"
+        );
+        assert!(Template::parse("${body:-{}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditionals() -> Result<()> {
+        let template = Template::parse(
+            "f({% if a %}${a}{% else %}0{% endif %}{% if not b %}, none{% endif %})",
+        )?;
+        assert_eq!(render(&template, &captures(&[("a", "1")])), "f(1, none)");
+        assert_eq!(render(&template, &captures(&[("b", "2")])), "f(0)");
+
+        assert!(Template::parse("{% if a %}x").is_err());
+        assert!(Template::parse("${a|shout}").is_err());
+        assert!(Template::parse("cost: ${").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lists() -> Result<()> {
+        let args = vec![Capture {
+            name: "arg".to_string(),
+            texts: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            separators: vec![",\n    ".to_string(), ", ".to_string()],
+        }];
+
+        let spread = Template::parse("f(${arg...}) ${arg|upper|join(\" | \")} ${arg}")?;
+        assert_eq!(render(&spread, &args), "f(a,\n    b, c) A | B | C a");
+
+        let each = Template::parse("g({% for x in arg sep \", \" %}wrap(${x}){% endfor %})")?;
+        assert_eq!(render(&each, &args), "g(wrap(a), wrap(b), wrap(c))");
+
+        let spaced = Template::parse("{%for  x\tin   arg  sep \" + \"  %}${x}{% endfor %}")?;
+        assert_eq!(render(&spaced, &args), "a + b + c");
+
+        assert!(Template::parse("{% for x in arg %}${x}").is_err());
+        assert!(Template::parse("{% for x in %}${x}{% endfor %}").is_err());
+        assert!(Template::parse("{% for x in arg by \", \" %}${x}{% endfor %}").is_err());
+        Ok(())
+    }
+}
@@ -32,16 +32,19 @@ fn test_function_call_rewrite() -> Result<()> {
 
 #[test]
 fn test_insertion_before_statement() -> Result<()> {
-    let source = "fn main() { process(); }";
+    let source = "fn main() {\n    process();\n}";
     let mut transformer = Transformer::new(source.to_string(), "rust")?;
 
     let query = "(expression_statement (call_expression function: (identifier) @name (#eq? @name \"process\"))) @target";
-    let template = "log(\"start\");\n    process();";
+    let template = "log(\"start\");\nprocess();";
 
     let _ = transformer.apply(query, template)?;
     let output = transformer.get_source();
 
-    assert_eq!(output, "fn main() { log(\"start\");\n    process(); }");
+    assert_eq!(
+        output,
+        "fn main() {\n    log(\"start\");\n    process();\n}"
+    );
     Ok(())
 }
 
@@ -95,6 +98,7 @@ fn test_multiline_error_context() {
 }
 
 #[test]
+#[cfg(feature = "lang-go")]
 fn test_go_template_validation() {
     let source = "1 + 2";
     let mut transformer = Transformer::new(source.to_string(), "go").unwrap();
@@ -130,3 +134,879 @@ fn test_batch_queries_sequential() -> Result<()> {
     assert_eq!(output, "fn main() { let x = add(1, 2); let y = bar(x); }");
     Ok(())
 }
+
+#[test]
+fn test_apply_with_rejects_edits() -> Result<()> {
+    let source = "fn main() { let a = 1 + 2; let b = 3 + 4; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let mut seen = Vec::new();
+    let mods = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+        &ApplyOptions::default(),
+        |_, m| {
+            seen.push(m.start_byte);
+            m.replacement == "add(3, 4)"
+        },
+    )?;
+
+    // Edits are offered in source order
+    assert_eq!(seen, vec![20, 35]);
+    assert_eq!(mods.len(), 1);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let a = 1 + 2; let b = add(3, 4); }"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_on_error_skip_reverts_only_bad_edit() -> Result<()> {
+    let source = "fn main() { let a = [1 + 2]; let b = 3 + 4; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    // Inside the array literal the template leaves an unbalanced bracket
+    let options = ApplyOptions {
+        on_error: OnError::Skip,
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r}",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(mods.len(), 0);
+    assert_eq!(transformer.get_source(), source);
+
+    let diagnostics = transformer.take_diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics[0].message.contains("Skipped edit"));
+    assert!(diagnostics[0].message.contains("Error at"));
+    assert!(transformer.take_diagnostics().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_on_error_skip_continues_with_valid_edits() -> Result<()> {
+    let source = "fn main() { let a = 1 + 2; foo(3 + 4); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        on_error: OnError::Skip,
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(let_declaration value: (_) @v) @target",
+        "let a = ${v}",
+        &options,
+        |_, _| true,
+    )?;
+    assert_eq!(mods.len(), 0);
+
+    let mods = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+        &options,
+        |_, _| true,
+    )?;
+    assert_eq!(mods.len(), 2);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let a = add(1, 2); foo(add(3, 4)); }"
+    );
+    assert_eq!(transformer.take_diagnostics().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_on_error_keep() -> Result<()> {
+    let source = "fn main() { let a = 1 + 2; let b = 3 + 4; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        on_error: OnError::Keep,
+        ..Default::default()
+    };
+    transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r}",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let a = add(1, 2; let b = add(3, 4; }"
+    );
+    assert_eq!(transformer.take_diagnostics().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_existing_errors_block_edits_by_default() {
+    let source = "fn main() { let x = 1 + 2; }\nfn broken( {\n";
+    let mut transformer = Transformer::new(source.to_string(), "rust").unwrap();
+
+    let result = transformer.apply(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_allow_existing_errors() -> Result<()> {
+    let source = "fn main() { let x = 1 + 2; }\nfn broken( {\n";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        allow_existing_errors: true,
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(mods.len(), 1);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let x = add(1, 2); }\nfn broken( {\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_allow_existing_errors_still_rejects_new_errors() {
+    let source = "fn main() { let x = 1 + 2; }\nfn broken( {\n";
+    let mut transformer = Transformer::new(source.to_string(), "rust").unwrap();
+
+    let options = ApplyOptions {
+        allow_existing_errors: true,
+        ..Default::default()
+    };
+    let result = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r}",
+        &options,
+        |_, _| true,
+    );
+
+    let err_msg = format!("{:?}", result.err().unwrap());
+    assert!(err_msg.contains("Transformation resulted in syntax error"));
+    assert!(err_msg.contains("add(1, 2;"));
+}
+
+#[test]
+fn test_nested_matches_outermost() -> Result<()> {
+    let source = "fn main() { let x = a + b + c; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let mods = transformer.apply(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+    )?;
+
+    assert_eq!(mods.len(), 1);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let x = add(a + b, c); }"
+    );
+    let diagnostics = transformer.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("nested inside the match"));
+    Ok(())
+}
+
+#[test]
+fn test_duplicate_matches_of_one_node() -> Result<()> {
+    // One match per argument, all with the same target and the same replacement
+    let source = "fn main() { f(a, b); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    let mods = transformer.apply(
+        "(call_expression function: (identifier) @target arguments: (arguments (identifier) @arg))",
+        "g",
+    )?;
+    assert_eq!(mods.len(), 1);
+    assert_eq!(transformer.get_source(), "fn main() { g(a, b); }");
+    assert!(transformer.take_diagnostics().is_empty());
+
+    // Different replacements of the same node are a real conflict
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    transformer.apply(
+        "(call_expression function: (identifier) @target arguments: (arguments (identifier) @arg))",
+        "${arg}",
+    )?;
+    assert_eq!(transformer.get_source(), "fn main() { a(a, b); }");
+    let diagnostics = transformer.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(
+        diagnostics[0]
+            .message
+            .contains("edits the same node differently")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_nested_matches_innermost() -> Result<()> {
+    let source = "fn main() { let x = a + b + c + d; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        overlap: Overlap::Innermost,
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(mods.len(), 3);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { let x = add(add(add(a, b), c), d); }"
+    );
+    assert!(transformer.take_diagnostics().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_nested_matches_error() {
+    let source = "fn main() { let x = a + b + c; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust").unwrap();
+
+    let options = ApplyOptions {
+        overlap: Overlap::Error,
+        ..Default::default()
+    };
+    let result = transformer.apply_with(
+        "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+        "add(${l}, ${r})",
+        &options,
+        |_, _| true,
+    );
+
+    let err_msg = format!("{:?}", result.err().unwrap());
+    assert!(err_msg.contains("Overlapping matches"));
+    assert_eq!(transformer.get_source(), source);
+}
+
+#[test]
+fn test_repeat_until_fixpoint() -> Result<()> {
+    let source = "fn main() { let x = f(f(f(1))); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    // Flatten one level of nesting per iteration
+    let query = "(call_expression function: (identifier) @f (#eq? @f \"f\") arguments: (arguments (call_expression function: (identifier) @g (#eq? @g \"f\") arguments: (arguments) @a))) @target";
+    let options = ApplyOptions {
+        repeat: Some(10),
+        ..Default::default()
+    };
+    transformer.apply_with(query, "f${a}", &options, |_, _| true)?;
+
+    assert_eq!(transformer.get_source(), "fn main() { let x = f(1); }");
+    assert!(
+        transformer
+            .take_diagnostics()
+            .iter()
+            .all(|d| !d.message.contains("fixpoint"))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_repeat_fixpoint_on_last_iteration() -> Result<()> {
+    let source = "fn main() { let x = f(f(f(1))); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    // Two iterations are exactly enough
+    let query = "(call_expression function: (identifier) @f (#eq? @f \"f\") arguments: (arguments (call_expression function: (identifier) @g (#eq? @g \"f\") arguments: (arguments) @a))) @target";
+    let options = ApplyOptions {
+        repeat: Some(2),
+        ..Default::default()
+    };
+    transformer.apply_with(query, "f${a}", &options, |_, _| true)?;
+
+    assert_eq!(transformer.get_source(), "fn main() { let x = f(1); }");
+    assert!(
+        transformer
+            .take_diagnostics()
+            .iter()
+            .all(|d| !d.message.contains("fixpoint"))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_repeat_stops_at_iteration_limit() -> Result<()> {
+    let source = "fn main() { let x = 1; }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        repeat: Some(3),
+        ..Default::default()
+    };
+    transformer.apply_with(
+        "(integer_literal) @target",
+        "(${target})",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(transformer.get_source(), "fn main() { let x = (((1))); }");
+    let diagnostics = transformer.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(
+        diagnostics[0]
+            .message
+            .contains("without reaching a fixpoint")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_repeat_detects_cycles() {
+    let source = "fn main() { f(1, 2); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust").unwrap();
+
+    // Swapping the arguments flips back and forth forever
+    let options = ApplyOptions {
+        repeat: Some(10),
+        ..Default::default()
+    };
+    let result = transformer.apply_with(
+        "(call_expression function: (identifier) @f arguments: (arguments (_) @a (_) @b)) @target",
+        "${f}(${b}, ${a})",
+        &options,
+        |_, _| true,
+    );
+
+    let err_msg = format!("{:?}", result.err().unwrap());
+    assert!(err_msg.contains("Rule does not converge"));
+}
+
+fn constraints(toml: &str) -> BTreeMap<String, CaptureConstraint> {
+    toml::from_str(toml).unwrap()
+}
+
+#[test]
+fn test_where_kind_and_regex() -> Result<()> {
+    let source = "fn main() { old_a(1); old_b(x); new_c(2); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        constraints: constraints(
+            r#"
+            name.regex = "^old_"
+            arg.kind = "integer_literal"
+            "#,
+        ),
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(call_expression function: (identifier) @name arguments: (arguments (_) @arg)) @target",
+        "renamed(${arg})",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(mods.len(), 1);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { renamed(1); old_b(x); new_c(2); }"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_where_count_and_not_inside() -> Result<()> {
+    let source = "fn main() { f(x); f(1); }\nmod tests { fn t() { f(2); } }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let options = ApplyOptions {
+        constraints: constraints(
+            r#"
+            arg.count = "< 1"
+            target.not_inside = "(mod_item)"
+            "#,
+        ),
+        ..Default::default()
+    };
+    let mods = transformer.apply_with(
+        "(call_expression arguments: (arguments (identifier)? @arg)) @target",
+        "g()",
+        &options,
+        |_, _| true,
+    )?;
+
+    assert_eq!(mods.len(), 1);
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { f(x); g(); }\nmod tests { fn t() { f(2); } }"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_where_unknown_capture() {
+    let mut transformer = Transformer::new("fn main() {}".to_string(), "rust").unwrap();
+    let options = ApplyOptions {
+        constraints: constraints("missing.kind = \"identifier\""),
+        ..Default::default()
+    };
+    let result = transformer.apply_with("(identifier) @target", "x", &options, |_, _| true);
+
+    let err_msg = format!("{:?}", result.err().unwrap());
+    assert!(err_msg.contains("'@missing'"));
+}
+
+#[test]
+fn test_template_undefined_capture() {
+    let mut transformer = Transformer::new("fn main() { f(1); }".to_string(), "rust").unwrap();
+    let result = transformer.apply("(call_expression function: (_) @f) @target", "${fn}()");
+
+    let err_msg = format!("{:?}", result.err().unwrap());
+    assert!(err_msg.contains("undefined capture 'fn'"));
+    assert_eq!(transformer.get_source(), "fn main() { f(1); }");
+}
+
+#[test]
+fn test_template_default_for_undefined_capture() -> Result<()> {
+    let mut transformer = Transformer::new("fn main() { f(1); }".to_string(), "rust")?;
+    transformer.apply(
+        "(call_expression function: (_) @f) @target",
+        "${f}(${args:-{}})",
+    )?;
+    assert_eq!(transformer.get_source(), "fn main() { f({}); }");
+    Ok(())
+}
+
+#[test]
+fn test_template_filters_and_conditionals() -> Result<()> {
+    let source = "fn main() { getValue(); set_value(x); }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    transformer.apply(
+        "(call_expression function: (identifier) @f arguments: (arguments (_)? @a)) @target",
+        "${f|snake_case|upper}({% if a %}${a}{% else %}None{% endif %})",
+    )?;
+
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() { GET_VALUE(None); SET_VALUE(x); }"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_template_quantified_captures() -> Result<()> {
+    let source = "fn main() {\n    f(a,\n      b, c);\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    transformer.apply(
+        "(call_expression function: (identifier) @f arguments: (arguments ((_) @arg \",\"?)*)) @target",
+        "${f}({% for a in arg sep \", \" %}Some(${a}){% endfor %}); g(${arg...})",
+    )?;
+
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() {\n    f(Some(a), Some(b), Some(c)); g(a,\n      b, c);\n}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_template_reindents_to_target_and_file_style() -> Result<()> {
+    let source = "fn main() {\n\tif ready {\n\t\trun(a,\n\t\t    b);\n\t}\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    // The template is written with 4 spaces; the file uses tabs
+    transformer.apply(
+        "(expression_statement (call_expression function: (identifier) @f (#eq? @f \"run\"))) @target",
+        "if enabled {\n    ${target}\n}",
+    )?;
+
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() {\n\tif ready {\n\t\tif enabled {\n\t\t\trun(a,\n\t\t\t    b);\n\t\t}\n\t}\n}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reindent_keeps_multiline_strings() -> Result<()> {
+    let source = "fn main() {\n    if x {\n        foo(\"a\nb\", r#\"\n  c\n\"#);\n    }\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    transformer.apply(
+        "(call_expression function: (identifier) @f (#eq? @f \"foo\") arguments: (arguments (_) @a (_) @b)) @target",
+        "bar(${a}, ${b})",
+    )?;
+    assert_eq!(
+        transformer.get_source(),
+        "fn main() {\n    if x {\n        bar(\"a\nb\", r#\"\n  c\n\"#);\n    }\n}"
+    );
+
+    // Wrapping indents the target's code, but not the lines inside its strings
+    let source = "fn main() {\n    run(\"a\nb\",\n        c);\n}";
+    let wrapped = apply_action(
+        source,
+        "(expression_statement (call_expression)) @target",
+        "if ok {\n    ${target}\n}",
+        Action::Wrap,
+    )?;
+    assert_eq!(
+        wrapped,
+        "fn main() {\n    if ok {\n        run(\"a\nb\",\n            c);\n    }\n}"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "lang-bash")]
+fn test_reindent_keeps_heredocs() -> Result<()> {
+    // A heredoc's body and terminator stay at their columns
+    let source = "if x; then\n  cat <<EOF\nhello\nEOF\nfi\n";
+    let mut transformer = Transformer::new(source.to_string(), "bash")?;
+    transformer.apply(
+        "(if_statement (redirected_statement) @body) @target",
+        "if y; then\n  ${body}\nfi",
+    )?;
+    assert_eq!(
+        transformer.get_source(),
+        "if y; then\n  cat <<EOF\nhello\nEOF\nfi\n"
+    );
+    Ok(())
+}
+
+fn apply_action(source: &str, query: &str, template: &str, action: Action) -> Result<String> {
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    let options = ApplyOptions {
+        action,
+        ..Default::default()
+    };
+    transformer.apply_with(query, template, &options, |_, _| true)?;
+    Ok(transformer.get_source().to_string())
+}
+
+#[test]
+fn test_action_insert_before_and_after() -> Result<()> {
+    let source = "fn main() {\n    process();\n    let x = f(1);\n}";
+    let statement = "(expression_statement (call_expression function: (identifier) @n (#eq? @n \"process\"))) @target";
+
+    assert_eq!(
+        apply_action(source, statement, "log(\"start\");", Action::InsertBefore)?,
+        "fn main() {\n    log(\"start\");\n    process();\n    let x = f(1);\n}"
+    );
+    assert_eq!(
+        apply_action(source, statement, "log(\"done\");", Action::InsertAfter)?,
+        "fn main() {\n    process();\n    log(\"done\");\n    let x = f(1);\n}"
+    );
+    // Inside a line the template is inserted as is
+    assert_eq!(
+        apply_action(
+            source,
+            "(integer_literal) @target",
+            "-",
+            Action::InsertBefore
+        )?,
+        "fn main() {\n    process();\n    let x = f(-1);\n}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_action_delete_cleans_up() -> Result<()> {
+    let source = "#[deprecated]\nfn old() {}\nfn main() {\n    f(1, 2, 3);\n    g(\n        4,\n        5\n    );\n}";
+
+    assert_eq!(
+        apply_action(source, "(attribute_item) @target", "", Action::Delete)?,
+        "fn old() {}\nfn main() {\n    f(1, 2, 3);\n    g(\n        4,\n        5\n    );\n}"
+    );
+    assert_eq!(
+        apply_action(
+            source,
+            "((integer_literal) @target (#match? @target \"^[135]$\"))",
+            "",
+            Action::Delete
+        )?,
+        "#[deprecated]\nfn old() {}\nfn main() {\n    f(2);\n    g(\n        4\n    );\n}"
+    );
+    assert_eq!(
+        apply_action(source, "(integer_literal) @target", "", Action::Delete)?,
+        "#[deprecated]\nfn old() {}\nfn main() {\n    f();\n    g(\n    );\n}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_action_delete_labeled_targets() -> Result<()> {
+    let source = "fn main() {\n    f(1, 2, 3);\n}";
+    let query = "(arguments . (integer_literal) @target.first (integer_literal) @target.last .)";
+    assert_eq!(
+        apply_action(source, query, "", Action::Delete)?,
+        "fn main() {\n    f(2);\n}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_action_wrap() -> Result<()> {
+    let source = "fn main() {\n    let x = compute(a,\n        b);\n}";
+    let query = "(call_expression) @target";
+
+    assert_eq!(
+        apply_action(source, query, "Some(${target})", Action::Wrap)?,
+        "fn main() {\n    let x = Some(compute(a,\n        b));\n}"
+    );
+    let result = apply_action(source, query, "Some(x)", Action::Wrap);
+    assert!(format!("{:?}", result.err().unwrap()).contains("'${target}'"));
+    Ok(())
+}
+
+fn labeled_options(templates: &[(&str, &str)]) -> ApplyOptions {
+    ApplyOptions {
+        target_templates: templates
+            .iter()
+            .map(|(label, t)| (label.to_string(), t.to_string()))
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_labeled_targets_edit_together() -> Result<()> {
+    let source = "fn get_id() -> u32 {\n    1\n}\nfn main() {}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    let query = "(function_item name: (identifier) @target.name return_type: (_) @target.ret)";
+
+    let options = labeled_options(&[("name", "${target.name}_v2"), ("ret", "u64")]);
+    let mods = transformer.apply_with(query, "", &options, |_, _| true)?;
+
+    assert_eq!(mods.len(), 2);
+    assert_eq!(
+        transformer.get_source(),
+        "fn get_id_v2() -> u64 {\n    1\n}\nfn main() {}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_labeled_targets_skip_reverts_all_edits() -> Result<()> {
+    let source = "fn get_id() -> u32 {\n    1\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    let query = "(function_item name: (identifier) @target.name return_type: (_) @target.ret)";
+
+    // The return type edit breaks the syntax, so the rename is reverted as well
+    let options = ApplyOptions {
+        on_error: OnError::Skip,
+        ..labeled_options(&[("name", "get_key"), ("ret", "Vec<")])
+    };
+    let mods = transformer.apply_with(query, "", &options, |_, _| true)?;
+
+    assert_eq!(mods.len(), 0);
+    assert_eq!(transformer.get_source(), source);
+    assert_eq!(transformer.take_diagnostics().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_labeled_targets_need_templates() {
+    let mut transformer = Transformer::new("fn main() {}".to_string(), "rust").unwrap();
+    let query = "(function_item name: (identifier) @target.name)";
+
+    let result = transformer.apply_with(query, "", &labeled_options(&[]), |_, _| true);
+    assert!(format!("{:?}", result.err().unwrap()).contains("no template is given for 'name'"));
+
+    let options = labeled_options(&[("name", "f"), ("body", "{}")]);
+    let result = transformer.apply_with(query, "", &options, |_, _| true);
+    assert!(format!("{:?}", result.err().unwrap()).contains("no '@target.body' capture"));
+}
+
+#[test]
+fn test_locals_queries_compile() -> Result<()> {
+    for lang in languages::LANGUAGES.iter().filter(|l| !l.locals.is_empty()) {
+        let mut transformer = Transformer::new(String::new(), lang.name)?;
+        let options = ApplyOptions::default();
+        let query = "((_) @a (_) @b (#refers-to? @a @b))";
+        transformer
+            .apply_with(query, "", &options, |_, _| true)
+            .with_context(|| format!("locals query of {}", lang.name))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_refers_to_predicate() -> Result<()> {
+    let source =
+        "fn f(x: i32, y: i32) -> i32 {\n    let a = x + x;\n    let b = x + y;\n    g + g\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    // `g` is not defined locally, so it does not resolve and is left alone
+    transformer.apply(
+        "(binary_expression left: (identifier) @l right: (identifier) @r (#refers-to? @l @r)) @target",
+        "2 * ${l}",
+    )?;
+    assert_eq!(
+        transformer.get_source(),
+        "fn f(x: i32, y: i32) -> i32 {\n    let a = 2 * x;\n    let b = x + y;\n    g + g\n}"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "lang-json")]
+fn test_refers_to_needs_locals_query() -> Result<()> {
+    let mut transformer = Transformer::new("{}".to_string(), "json")?;
+    let result = transformer.apply("((_) @a (_) @b (#refers-to? @a @b))", "");
+    assert!(format!("{:?}", result.err().unwrap()).contains("has no locals query"));
+    Ok(())
+}
+
+#[test]
+fn test_where_refers_to_limits_to_one_definition() -> Result<()> {
+    let source = "fn a(x: i32) -> i32 {\n    let y = x + 1;\n    { let x = 2; x + y }\n}\nfn b(x: i32) -> i32 { x * 2 }";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+
+    let definition = "(function_item name: (identifier) @f (#eq? @f \"a\") parameters: (parameters (parameter pattern: (identifier) @definition)))";
+    let options = ApplyOptions {
+        constraints: BTreeMap::from([(
+            "target".to_string(),
+            CaptureConstraint {
+                refers_to: Some(definition.to_string()),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    transformer.apply_with("(identifier) @target", "value", &options, |_, _| true)?;
+
+    // The shadowing `x` in the inner block and the parameter of `b` are different variables
+    assert_eq!(
+        transformer.get_source(),
+        "fn a(value: i32) -> i32 {\n    let y = value + 1;\n    { let x = 2; x + y }\n}\nfn b(x: i32) -> i32 { x * 2 }"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_let_refers_to_previous_definition() -> Result<()> {
+    let source = "fn f(x: i32) -> i32 {\n    let x = x + 1;\n    x\n}";
+    let mut transformer = Transformer::new(source.to_string(), "rust")?;
+    let options = ApplyOptions {
+        constraints: BTreeMap::from([(
+            "target".to_string(),
+            CaptureConstraint {
+                refers_to: Some("(parameter pattern: (identifier) @definition)".to_string()),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    transformer.apply_with("(identifier) @target", "n", &options, |_, _| true)?;
+
+    // The new `x` is only visible after its `let`, so its initializer still uses the parameter
+    assert_eq!(
+        transformer.get_source(),
+        "fn f(n: i32) -> i32 {\n    let x = n + 1;\n    x\n}"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(all(
+    feature = "lang-go",
+    feature = "lang-python",
+    feature = "lang-cpp",
+    feature = "lang-java",
+    feature = "lang-bash"
+))]
+fn test_refers_to_in_other_languages() -> Result<()> {
+    let cases = [
+        (
+            "go",
+            "func f(x int) int {\n\tx := x + 1\n\treturn x\n}",
+            "(parameter_declaration name: (identifier) @definition)",
+            "func f(n int) int {\n\tx := n + 1\n\treturn x\n}",
+        ),
+        (
+            "python",
+            "def f(x):\n    x = x + 1\n    return x",
+            "(parameters (identifier) @definition)",
+            "def f(n):\n    x = n + 1\n    return x",
+        ),
+        (
+            "cpp",
+            "int f(int x) {\n  for (int x : xs) { g(x); }\n  return x;\n}",
+            "(parameter_declaration declarator: (identifier) @definition)",
+            "int f(int n) {\n  for (int x : xs) { g(x); }\n  return n;\n}",
+        ),
+        (
+            "java",
+            "int f(int x) {\n  for (int x : xs) { g(x); }\n  return x;\n}",
+            "(formal_parameter name: (identifier) @definition)",
+            "int f(int n) {\n  for (int x : xs) { g(x); }\n  return n;\n}",
+        ),
+        (
+            "bash",
+            "x=1\nx=$((x + 1))\necho $x",
+            "(program . (variable_assignment name: (variable_name) @definition))",
+            "n=1\nx=$((n + 1))\necho $x",
+        ),
+    ];
+    for (lang, source, definition, expected) in cases {
+        let mut transformer = Transformer::new(source.to_string(), lang)?;
+        let options = ApplyOptions {
+            constraints: BTreeMap::from([(
+                "target".to_string(),
+                CaptureConstraint {
+                    refers_to: Some(definition.to_string()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let query = if lang == "bash" {
+            "(variable_name) @target"
+        } else {
+            "(identifier) @target"
+        };
+        transformer.apply_with(query, "n", &options, |_, _| true)?;
+        assert_eq!(transformer.get_source(), expected, "{}", lang);
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "lang-bash")]
+fn test_embedded_code_edits_stay_in_ranges() -> Result<()> {
+    let source = "run: echo a; echo b\n| text |\nrun: echo c";
+    let ranges = vec![5..20, 34..40];
+    let mut transformer = Transformer::new_embedded(source.to_string(), "bash", ranges)?;
+
+    let mods = transformer.apply(
+        "((command_name (word) @target) (#eq? @target \"echo\"))",
+        "printf",
+    )?;
+    assert_eq!(mods.len(), 3);
+    assert_eq!(mods[0].start_byte, 34);
+    assert_eq!(
+        transformer.get_source(),
+        "run: printf a; printf b\n| text |\nrun: printf c"
+    );
+
+    // The program spans the text between the ranges, so replacing it is refused
+    let mods = transformer.apply("(program) @target", "exit")?;
+    assert!(mods.is_empty());
+    let diagnostics = transformer.take_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("outside the embedded code"));
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use std::fs;
+use std::process::Command;
 use tempfile::tempdir;
 
 #[test]
@@ -17,11 +18,8 @@ fn test_batch_processing_files() -> Result<()> {
             "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target".to_string(),
         ],
         template: vec!["add(${l}, ${r})".to_string()],
-        rule_file: None,
         in_place: true,
-        language: None,
-        list_languages: false,
-        json: false,
+        ..Default::default()
     };
 
     graft::cli::run_with_args(cli)?;
@@ -63,13 +61,9 @@ fn test_rule_file_loading() -> Result<()> {
 
     let cli = graft::cli::Cli {
         files: vec![target_file.to_string_lossy().to_string()],
-        query: vec![],
-        template: vec![],
         rule_file: Some(rules_file),
         in_place: true,
-        language: None,
-        list_languages: false,
-        json: false,
+        ..Default::default()
     };
 
     graft::cli::run_with_args(cli)?;
@@ -79,3 +73,610 @@ fn test_rule_file_loading() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_diff_mode_leaves_files_untouched() -> Result<()> {
+    let dir = tempdir()?;
+    let target_file = dir.path().join("target.rs");
+    let source = "fn main() { let x = 1 + 2; }";
+    fs::write(&target_file, source)?;
+
+    let cli = graft::cli::Cli {
+        files: vec![target_file.to_string_lossy().to_string()],
+        query: vec![
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target".to_string(),
+        ],
+        template: vec!["add(${l}, ${r})".to_string()],
+        diff: true,
+        ..Default::default()
+    };
+
+    graft::cli::run_with_args(cli)?;
+
+    assert_eq!(fs::read_to_string(&target_file)?, source);
+
+    // Paths are relative to the working directory, so `git apply -p1` works from there
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir("/")
+        .arg(&target_file)
+        .args([
+            "-q",
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+            "-t",
+            "add(${l}, ${r})",
+            "--diff",
+        ])
+        .output()?;
+    assert!(output.status.success());
+    let path = target_file.to_string_lossy();
+    let path = path.trim_start_matches('/');
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!(
+            "--- a/{path}\n+++ b/{path}\n@@ -1 +1 @@\n-fn main() {{ let x = 1 + 2; }}\n\\ No newline at end of file\n+fn main() {{ let x = add(1, 2); }}\n\\ No newline at end of file\n"
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_mode_reports_and_fails() -> Result<()> {
+    let dir = tempdir()?;
+    let dirty = dir.path().join("dirty.rs");
+    let clean = dir.path().join("clean.rs");
+    fs::write(&dirty, "fn main() { foo(1); }")?;
+    fs::write(&clean, "fn main() { bar(1); }")?;
+
+    let query = "(call_expression function: (identifier) @n (#eq? @n \"foo\") arguments: (arguments) @a) @target";
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&dirty)
+        .arg(&clean)
+        .args(["-q", query, "-t", "bar${a}", "--check"])
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains(&format!("{}: query #1", dirty.display())));
+    assert!(!stdout.contains(&clean.display().to_string()));
+    assert!(stdout.contains("1 file(s) would be modified"));
+    assert_eq!(fs::read_to_string(&dirty)?, "fn main() { foo(1); }");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&clean)
+        .args(["-q", query, "-t", "bar${a}", "--check"])
+        .output()?;
+    assert!(output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn test_directory_argument_is_walked() -> Result<()> {
+    let dir = tempdir()?;
+    let nested = dir.path().join("src/nested");
+    fs::create_dir_all(&nested)?;
+    fs::write(dir.path().join(".gitignore"), "ignored.rs\n")?;
+    fs::write(nested.join("a.rs"), "fn main() { let x = 1 + 2; }")?;
+    fs::write(nested.join("ignored.rs"), "fn main() { let x = 1 + 2; }")?;
+
+    let cli = graft::cli::Cli {
+        files: vec![dir.path().to_string_lossy().to_string()],
+        query: vec![
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target".to_string(),
+        ],
+        template: vec!["add(${l}, ${r})".to_string()],
+        in_place: true,
+        ..Default::default()
+    };
+
+    graft::cli::run_with_args(cli)?;
+
+    assert!(fs::read_to_string(nested.join("a.rs"))?.contains("add(1, 2)"));
+    assert!(fs::read_to_string(nested.join("ignored.rs"))?.contains("1 + 2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_empty_directory_does_not_read_stdin() -> Result<()> {
+    let dir = tempdir()?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(dir.path())
+        .args(["-q", "(identifier) @target", "-t", "x", "-l", "rust"])
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("No files matched"));
+    Ok(())
+}
+
+#[test]
+fn test_interactive_applies_accepted_matches() -> Result<()> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = tempdir()?;
+    let target_file = dir.path().join("target.rs");
+    fs::write(&target_file, "fn main() { let a = 1 + 2; let b = 3 + 4; }")?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&target_file)
+        .args([
+            "-q",
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+            "-t",
+            "add(${l}, ${r})",
+            "--interactive",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(b"n\ny\n")?;
+    assert!(child.wait()?.success());
+
+    assert_eq!(
+        fs::read_to_string(&target_file)?,
+        "fn main() { let a = 1 + 2; let b = add(3, 4); }"
+    );
+
+    // Quitting before accepting anything writes neither the file nor a journal
+    let past = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&target_file)?
+        .set_modified(past)?;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .arg(&target_file)
+        .args([
+            "-q",
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+            "-t",
+            "add(${l}, ${r})",
+            "--interactive",
+            "--atomic",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(b"q\n")?;
+    assert!(child.wait()?.success());
+    assert_eq!(fs::metadata(&target_file)?.modified()?, past);
+    assert!(!dir.path().join(".graft/journal.json").exists());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&target_file)
+        .args([
+            "-q",
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+            "-t",
+            "add(${l}, ${r})",
+            "--interactive",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(b"q\n")?;
+    assert!(child.wait()?.success());
+    assert_eq!(fs::metadata(&target_file)?.modified()?, past);
+
+    Ok(())
+}
+
+#[test]
+fn test_atomic_writes_nothing_when_a_file_fails() -> Result<()> {
+    let dir = tempdir()?;
+    let good = dir.path().join("good.rs");
+    let bad = dir.path().join("bad.rs");
+    fs::write(&good, "fn main() { let x = 1 + 2; }")?;
+    // The template breaks this file: `1 + 2` becomes an unterminated call
+    fs::write(&bad, "fn main() { let x = [1 + 2]; }")?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .args(["good.rs", "bad.rs", "--in-place", "--atomic"])
+        .args([
+            "-q",
+            "(array_expression (binary_expression) @b) @target",
+            "-t",
+            "[add(${b}]",
+        ])
+        .output()?;
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(fs::read_to_string(&good)?, "fn main() { let x = 1 + 2; }");
+    assert_eq!(fs::read_to_string(&bad)?, "fn main() { let x = [1 + 2]; }");
+    assert!(!dir.path().join(".graft/journal.json").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_atomic_then_undo() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("a.rs");
+    fs::write(&file, "fn main() { let x = 1 + 2; }")?;
+
+    let status = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .args(["a.rs", "--in-place", "--atomic"])
+        .args([
+            "-q",
+            "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target",
+            "-t",
+            "add(${l}, ${r})",
+        ])
+        .status()?;
+    assert!(status.success());
+    assert_eq!(
+        fs::read_to_string(&file)?,
+        "fn main() { let x = add(1, 2); }"
+    );
+
+    // Undo does not read the project config, so a broken one cannot get in the way
+    fs::write(dir.path().join("graft.toml"), "rule_files = [")?;
+    let status = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .arg("--undo")
+        .status()?;
+    assert!(status.success());
+    assert_eq!(fs::read_to_string(&file)?, "fn main() { let x = 1 + 2; }");
+
+    Ok(())
+}
+
+#[test]
+fn test_rule_on_error_skip() -> Result<()> {
+    let dir = tempdir()?;
+    let target_file = dir.path().join("target.rs");
+    let rules_file = dir.path().join("rules.toml");
+
+    fs::write(
+        &target_file,
+        "fn main() { let a = [1 + 2]; let b = 3 + 4; }",
+    )?;
+    fs::write(
+        &rules_file,
+        r#"
+[[rules]]
+name = "wrap-add"
+language = "rust"
+on_error = "skip"
+query = "(binary_expression left: (_) @l operator: \"+\" right: (_) @r) @target"
+template = "(add(${l}, ${r})"
+"#,
+    )?;
+
+    let cli = graft::cli::Cli {
+        files: vec![target_file.to_string_lossy().to_string()],
+        rule_file: Some(rules_file),
+        in_place: true,
+        ..Default::default()
+    };
+
+    graft::cli::run_with_args(cli)?;
+
+    // Both edits leave an unbalanced parenthesis, so both are skipped and the file is unchanged
+    let output = fs::read_to_string(target_file)?;
+    assert_eq!(output, "fn main() { let a = [1 + 2]; let b = 3 + 4; }");
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_across_files() -> Result<()> {
+    let dir = tempdir()?;
+    let lib = dir.path().join("lib.rs");
+    let main = dir.path().join("main.rs");
+    fs::write(&lib, "pub fn load() {}\n")?;
+    fs::write(&main, "use lib::load;\nfn main() { load(); }\n")?;
+
+    let status = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .args([
+            "rename", "load", "fetch", ".", "--kind", "function", "-l", "rust", "-i",
+        ])
+        .status()?;
+    assert!(status.success());
+    assert_eq!(fs::read_to_string(&lib)?, "pub fn fetch() {}\n");
+    assert_eq!(
+        fs::read_to_string(&main)?,
+        "use lib::fetch;\nfn main() { fetch(); }\n"
+    );
+
+    // `fetch` now exists, so renaming `main` to it collides and nothing is written
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .args([
+            "rename", "main", "fetch", ".", "--kind", "function", "-l", "rust", "-i",
+        ])
+        .output()?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("lib.rs:1: A function named 'fetch' already exists")
+    );
+    assert_eq!(
+        fs::read_to_string(&main)?,
+        "use lib::fetch;\nfn main() { fetch(); }\n"
+    );
+
+    // The aborted rename discarded the first one's journal, so there is nothing to undo
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .arg("--undo")
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No transaction to undo"));
+    assert_eq!(fs::read_to_string(&lib)?, "pub fn fetch() {}\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_format_changed_lines_and_report_failures() -> Result<()> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = tempdir()?;
+    let file = dir.path().join("a.rs");
+    fs::write(
+        &file,
+        "fn main() {\n    let a = [ 1 ];\n    let b = f(1);\n}\n",
+    )?;
+    let query = "(call_expression function: (identifier) @f arguments: (arguments (_) @x)) @target";
+
+    // Only the rewritten line is normalized; the untouched `[ 1 ]` keeps its spacing
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&file)
+        .args(["-q", query, "-t", "g(  ${x} ,0 )", "--format", "builtin"])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "fn main() {\n    let a = [ 1 ];\n    let b = g(1, 0);\n}\n"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .arg(&file)
+        .args(["-q", query, "-t", "g(${x})", "--format", "false", "--json"])
+        .output()?;
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let errors = json["formatter_errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["filename"], file.to_string_lossy().as_ref());
+    assert!(
+        errors[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Formatter 'false' failed")
+    );
+
+    // Source read from stdin is named after the language's usual extension
+    let mut child = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .args(["-l", "rust", "-q", query, "-t", "g(${x})"])
+        .args(["--format", "echo {file}", "--format-scope", "file"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"fn main() { f(1); }\n")?;
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "stdin.rs\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "lang-cpp")]
+fn test_project_config_languages_rules_and_excludes() -> Result<()> {
+    let dir = tempdir()?;
+    let root = dir.path();
+    fs::create_dir_all(root.join("src"))?;
+    fs::create_dir_all(root.join("vendor"))?;
+    fs::write(
+        root.join("graft.toml"),
+        "rule_files = [\"rules.toml\"]\nexclude = [\"vendor/**\"]\n\n[languages]\n\"*.h\" = \"cpp\"\n",
+    )?;
+    // `class_specifier` only exists in the C++ grammar, so `.h` files must be parsed as C++
+    fs::write(
+        root.join("rules.toml"),
+        r#"
+[[rules]]
+name = "rename-widget"
+language = "cpp"
+query = '(class_specifier name: (type_identifier) @target (#eq? @target "Widget"))'
+template = "Gadget"
+"#,
+    )?;
+    fs::write(root.join("src/api.h"), "class Widget {};\n")?;
+    fs::write(root.join("vendor/lib.h"), "class Widget {};\n")?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(root.join("src"))
+        .args(["..", "-i"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("src/api.h"))?,
+        "class Gadget {};\n"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("vendor/lib.h"))?,
+        "class Widget {};\n"
+    );
+
+    // Without the config there are no rules to run
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(root.join("src"))
+        .args(["..", "--no-config"])
+        .output()?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(
+    feature = "lang-bash",
+    feature = "lang-yaml",
+    feature = "lang-markdown"
+))]
+fn test_rules_apply_to_embedded_code() -> Result<()> {
+    let dir = tempdir()?;
+    let root = dir.path();
+    fs::write(
+        root.join("rules.toml"),
+        r#"
+[[rules]]
+name = "apt-get"
+language = "bash"
+query = '((command_name (word) @target) (#eq? @target "apt"))'
+template = "apt-get"
+"#,
+    )?;
+    fs::write(
+        root.join("ci.yml"),
+        "steps:\n  - run: |\n      apt update\n      apt install -y make\n  - run: apt upgrade\n",
+    )?;
+    fs::write(
+        root.join("README.md"),
+        "Run `apt update` first:\n\n```sh\napt update\n```\n",
+    )?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(root)
+        .args([
+            "ci.yml",
+            "README.md",
+            "-f",
+            "rules.toml",
+            "-i",
+            "--no-config",
+        ])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("ci.yml"))?,
+        "steps:\n  - run: |\n      apt-get update\n      apt-get install -y make\n  - run: apt-get upgrade\n"
+    );
+    // Inline code is prose, only the code block is Bash
+    assert_eq!(
+        fs::read_to_string(root.join("README.md"))?,
+        "Run `apt update` first:\n\n```sh\napt-get update\n```\n"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(not(feature = "lang-make"))]
+fn test_file_of_disabled_language_names_feature() -> Result<()> {
+    let dir = tempdir()?;
+    fs::write(dir.path().join("Makefile"), "all:\n\techo hi\n")?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(dir.path())
+        .args(["Makefile", "--query", "(word) @target", "--template", "x"])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Language 'Make' is not included in this build of graft"),
+        "{}",
+        stderr
+    );
+    Ok(())
+}
+
+/// Whether the C compiler grammars are built with is installed.
+fn has_compiler() -> bool {
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    Command::new(compiler)
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+#[test]
+fn test_project_config_builds_and_uses_grammar() -> Result<()> {
+    if !has_compiler() {
+        eprintln!("skipping: no C compiler");
+        return Ok(());
+    }
+    let dir = tempdir()?;
+    let root = dir.path();
+    let fixture =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/json-grammar");
+    fs::write(
+        root.join("graft.toml"),
+        format!(
+            r#"
+rule_files = ["rules.toml"]
+
+[grammars.jsonc]
+source = {:?}
+symbol = "tree_sitter_json"
+extensions = ["jsonc"]
+"#,
+            fixture
+        ),
+    )?;
+    fs::write(
+        root.join("rules.toml"),
+        r#"
+[[rules]]
+name = "rename-key"
+language = "jsonc"
+query = '((pair key: (string (string_content) @target)) (#eq? @target "colour"))'
+template = "color"
+"#,
+    )?;
+    fs::write(root.join("theme.jsonc"), "{\"colour\": \"red\"}\n")?;
+
+    // Grammars run native code, so they are only built and loaded when asked to
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(root)
+        .env("GRAFT_CACHE_DIR", root.join("cache"))
+        .args(["theme.jsonc", "-i"])
+        .output()?;
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("not loading grammar(s) jsonc; pass --load-grammars")
+    );
+    assert!(!root.join("cache").exists());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_graft"))
+        .current_dir(root)
+        .env("GRAFT_CACHE_DIR", root.join("cache"))
+        .args(["theme.jsonc", "-i", "--load-grammars"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("theme.jsonc"))?,
+        "{\"color\": \"red\"}\n"
+    );
+    Ok(())
+}